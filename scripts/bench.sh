@@ -0,0 +1,46 @@
+#!/bin/bash
+# Measures auth throughput under concurrency with `wrk` against a local release build.
+# Runs one pass where every request is an already authorized IP (the read path) and one
+# where every request logs in with basic auth (read path plus queued IP updates).
+set -e
+CONNECTIONS="${CONNECTIONS:-64}"
+THREADS="${THREADS:-4}"
+DURATION="${DURATION:-10s}"
+IPS="${IPS:-5000}"
+LISTEN="${LISTEN:-127.0.0.1:3041}"
+
+WORK_DIR="$(mktemp -d)"
+trap 'kill $RAUTHY_PID 2>/dev/null; rm -rf "$WORK_DIR"' EXIT
+
+cargo build --release
+export AUTH_FILE="$WORK_DIR/auth.json"
+export LISTEN
+export RUST_LOG=warn
+
+# Seed the auth file with a user and a large allow list
+{
+  printf '{"passwords":{"YmVuY2g6YmVuY2g=":"bench"},"tokens":{},"commands":{},"domains":[],"ips":{'
+  for i in $(seq 1 "$IPS"); do
+    [ "$i" -gt 1 ] && printf ','
+    printf '"10.%d.%d.%d":["bench"]' $((i / 65536 % 256)) $((i / 256 % 256)) $((i % 256))
+  done
+  printf '}}'
+} >"$AUTH_FILE"
+
+./target/release/rauthy &
+RAUTHY_PID=$!
+sleep 1
+
+echo "== Authorized IP, $CONNECTIONS connections"
+wrk -t "$THREADS" -c "$CONNECTIONS" -d "$DURATION" -H "X-Forwarded-For: 10.0.0.1" "http://$LISTEN/"
+
+echo "== Basic auth from new IPs, $CONNECTIONS connections"
+cat >"$WORK_DIR/login.lua" <<'EOF'
+counter = 0
+request = function()
+  counter = counter + 1
+  local ip = string.format("172.16.%d.%d", math.floor(counter / 256) % 256, counter % 256)
+  return wrk.format("GET", "/", { ["X-Forwarded-For"] = ip, ["Authorization"] = "Basic YmVuY2g6YmVuY2g=" })
+end
+EOF
+wrk -t "$THREADS" -c "$CONNECTIONS" -d "$DURATION" -s "$WORK_DIR/login.lua" "http://$LISTEN/"
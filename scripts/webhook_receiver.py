@@ -0,0 +1,38 @@
+#!/usr/bin/env python3
+# Local stand-in for a webhook endpoint, prints every delivery and checks its signature.
+#   WEBHOOK_SECRET=secret ./scripts/webhook_receiver.py 8099
+#   WEBHOOK_URLS=http://127.0.0.1:8099/ WEBHOOK_SECRET=secret ./rauthy
+# Set STATUS to answer with another status code, e.g. STATUS=500 to watch the retries.
+import hashlib
+import hmac
+import os
+import sys
+import time
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+SECRET = os.environ.get("WEBHOOK_SECRET", "").encode()
+STATUS = int(os.environ.get("STATUS", "200"))
+
+
+class Handler(BaseHTTPRequestHandler):
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers.get("Content-Length", 0)))
+        signature = self.headers.get("X-Rauthy-Signature", "")
+        timestamp = self.headers.get("X-Rauthy-Timestamp", "")
+        if SECRET:
+            payload = timestamp.encode() + b"." + body
+            expected = "sha256=" + hmac.new(SECRET, payload, hashlib.sha256).hexdigest()
+            valid = "valid" if hmac.compare_digest(signature, expected) else "INVALID"
+            if valid == "valid" and abs(time.time() - int(timestamp)) > 300:
+                valid = "STALE"
+        else:
+            valid = "unsigned"
+        print(f"{self.headers.get('X-Rauthy-Event')} ({valid} signature): {body.decode()}", flush=True)
+        self.send_response(STATUS)
+        self.end_headers()
+
+    def log_message(self, *args):
+        pass
+
+
+HTTPServer(("127.0.0.1", int(sys.argv[1]) if len(sys.argv) > 1 else 8099), Handler).serve_forever()
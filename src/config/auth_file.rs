@@ -0,0 +1,233 @@
+use crate::error::RauthyError;
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// The JSON auth file on disk.
+/// Every access holds an advisory lock on `<path>.lock` so the CLI and the server never interleave,
+/// and writes go to a temporary file that is fsynced and renamed over the original.
+#[derive(Clone, Debug)]
+pub struct AuthFile {
+    path: PathBuf,
+    backups: usize,
+    /// Set when the caller already holds the exclusive lock
+    held: Option<Arc<AuthFileLock>>,
+}
+
+/// An exclusive lock on the auth file held across a whole load-modify-write cycle, released on drop
+#[derive(Debug)]
+pub struct AuthFileLock(File);
+
+impl AuthFile {
+    pub fn new(path: String, backups: usize) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            backups,
+            held: None,
+        }
+    }
+
+    /// Reads and writes under `lock` instead of taking the lock for each access
+    pub fn with_lock(mut self, lock: Option<Arc<AuthFileLock>>) -> Self {
+        self.held = lock;
+        self
+    }
+
+    /// Blocks until no other process reads or writes the file
+    pub fn lock_exclusive(&self) -> Result<AuthFileLock, RauthyError> {
+        let lock = self.lock()?;
+        lock.lock_exclusive()?;
+        Ok(AuthFileLock(lock))
+    }
+
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(suffix);
+        self.path.with_file_name(name)
+    }
+
+    fn lock(&self) -> Result<File, RauthyError> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(self.sibling(".lock"))?)
+    }
+
+    /// Returns `None` if the file does not exist yet
+    pub fn read(&self) -> Result<Option<String>, RauthyError> {
+        let lock = match self.held {
+            Some(_) => None,
+            None => {
+                let lock = self.lock()?;
+                lock.lock_shared()?;
+                Some(lock)
+            }
+        };
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(lock) = lock {
+            lock.unlock()?;
+        }
+        Ok(contents)
+    }
+
+    pub fn write(&self, contents: &str) -> Result<(), RauthyError> {
+        let lock = match self.held {
+            Some(_) => None,
+            None => Some(self.lock_exclusive()?),
+        };
+
+        let original = match fs::metadata(&self.path) {
+            Ok(metadata) => Some(metadata),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if original.is_some() {
+            self.rotate_backups()?;
+        }
+
+        let tmp_path = self.sibling(".tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        // The replacement keeps the original's mode and, where allowed, its owner
+        if let Some(original) = original.as_ref() {
+            tmp.set_permissions(original.permissions())?;
+            if let Err(e) =
+                std::os::unix::fs::chown(&tmp_path, Some(original.uid()), Some(original.gid()))
+            {
+                log::debug!("Unable to keep the owner of {:?}: {}", self.path, e);
+            }
+        }
+        tmp.write_all(contents.as_bytes())?;
+        tmp.sync_all()?;
+        drop(tmp);
+        fs::rename(&tmp_path, &self.path)?;
+        // Persist the rename itself
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+
+        if let Some(AuthFileLock(lock)) = lock {
+            lock.unlock()?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the file can be read without taking the lock, which is safe as `write` replaces it by a rename
+    pub fn check_readable(&self) -> Result<(), RauthyError> {
+        match fs::read_to_string(&self.path) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Checks that `write` can create and rename files next to the auth file without changing it.
+    /// Never waits for the lock, while another process holds it that process is writing and the probe is skipped.
+    pub fn check_writable(&self) -> Result<(), RauthyError> {
+        let lock = self.lock()?;
+        if let Err(e) = lock.try_lock_exclusive() {
+            if e.kind() == fs2::lock_contended_error().kind() {
+                log::debug!("Skipping the write check, {:?} is locked", self.path);
+                return Ok(());
+            }
+            return Err(e.into());
+        }
+        let tmp_path = self.sibling(".tmp");
+        File::create(&tmp_path)?;
+        fs::remove_file(&tmp_path)?;
+        if self.path.exists() {
+            OpenOptions::new().append(true).open(&self.path)?;
+        }
+        lock.unlock()?;
+        Ok(())
+    }
+
+    /// Shifts `<path>.1` .. `<path>.N` along and copies the current file to `<path>.1`
+    fn rotate_backups(&self) -> Result<(), RauthyError> {
+        if self.backups == 0 {
+            return Ok(());
+        }
+        for index in (1..self.backups).rev() {
+            let from = self.sibling(&format!(".{}", index));
+            if from.exists() {
+                fs::rename(&from, self.sibling(&format!(".{}", index + 1)))?;
+            }
+        }
+        fs::copy(&self.path, self.sibling(".1"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn temp_file() -> (PathBuf, AuthFile) {
+        let dir = std::env::temp_dir().join(format!("rauthy-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("auth.json");
+        let file = AuthFile::new(path.to_string_lossy().to_string(), 2);
+        (dir, file)
+    }
+
+    #[test]
+    fn checks_never_wait_for_a_held_lock() {
+        let (dir, file) = temp_file();
+        fs::write(&file.path, "{}").unwrap();
+        file.check_writable().unwrap();
+        let held = file.lock_exclusive().unwrap();
+        file.check_readable().unwrap();
+        file.check_writable().unwrap();
+        assert!(!file.sibling(".tmp").exists());
+        drop(held);
+        file.check_writable().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn write_keeps_permissions() {
+        let (dir, file) = temp_file();
+        fs::write(&file.path, "{}").unwrap();
+        fs::set_permissions(&file.path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        file.write("{\"ips\":{}}").unwrap();
+
+        let mode = fs::metadata(&file.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(file.read().unwrap().unwrap(), "{\"ips\":{}}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn write_rotates_backups() {
+        let (dir, file) = temp_file();
+        for contents in &["1", "2", "3", "4"] {
+            file.write(contents).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&file.path).unwrap(), "4");
+        assert_eq!(fs::read_to_string(file.sibling(".1")).unwrap(), "3");
+        assert_eq!(fs::read_to_string(file.sibling(".2")).unwrap(), "2");
+        assert!(!file.sibling(".3").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn held_lock_is_reused() {
+        let (dir, file) = temp_file();
+        let lock = Arc::new(file.lock_exclusive().unwrap());
+        let locked = file.clone().with_lock(Some(lock));
+
+        // Would block on its own lock if it locked again
+        locked.write("{}").unwrap();
+        assert_eq!(locked.read().unwrap().unwrap(), "{}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,10 +1,25 @@
-use crate::config::command::UserCommand;
+use crate::config::command::{Hook, UserCommand};
+use crate::config::deny_list::DenyList;
+use crate::config::invite::{self, Invite};
+use crate::config::secret::Secret;
+use crate::config::totp;
+use crate::config::user::{Login, User, UserDetails};
+use crate::error::RauthyError;
+use chrono::{DateTime, Utc};
 use regex::Regex;
 use serde::export::Formatter;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::hash::Hash;
 use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+// Tests hash with the lowest cost bcrypt allows so they don't take seconds
+#[cfg(not(test))]
+const PASSWORD_HASH_COST: u32 = bcrypt::DEFAULT_COST;
+#[cfg(test)]
+const PASSWORD_HASH_COST: u32 = 4;
 
 #[derive(Hash, Serialize, Deserialize, Debug, Default, Clone, Eq, PartialEq)]
 pub struct Username(String);
@@ -37,24 +52,321 @@ impl From<&str> for Username {
     }
 }
 
-#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+/// Password hashes that a password was already checked against, so bcrypt only runs once per password.
+/// Holds the SHA-256 of hash and password keyed by the hash, shared by every copy of the auth options.
+#[derive(Default, Clone)]
+struct VerifiedPasswords(Arc<Mutex<HashMap<String, Vec<u8>>>>);
+
+impl VerifiedPasswords {
+    fn digest(hash: &str, password: &str) -> Vec<u8> {
+        Sha256::new()
+            .chain(hash.as_bytes())
+            .chain(password.as_bytes())
+            .finalize()
+            .to_vec()
+    }
+
+    fn verify(&self, hash: &str, password: &str) -> bool {
+        let digest = Self::digest(hash, password);
+        if self.0.lock().unwrap().get(hash) == Some(&digest) {
+            return true;
+        }
+        let verified = bcrypt::verify(password, hash).unwrap_or(false);
+        if verified {
+            self.0.lock().unwrap().insert(hash.to_string(), digest);
+        }
+        verified
+    }
+}
+
+/// A salted bcrypt hash of `password`
+fn hash_password(password: &str) -> String {
+    // Only fails for an invalid cost or without OS randomness, which `Uuid::new_v4` panics on as well
+    bcrypt::hash(password, PASSWORD_HASH_COST).expect("Unable to hash password")
+}
+
+fn is_password_hash(key: &str) -> bool {
+    key.starts_with("$2")
+}
+
+/// The IP grants touched by removing a user
+#[derive(Debug, Default)]
+pub struct RemovedUser {
+    /// IPs still granted to other users
+    pub updated_ips: Vec<IpAddr>,
+    /// IPs that had no other user and were dropped
+    pub removed_ips: Vec<IpAddr>,
+}
+
+/// `Debug` is written by hand so password and token keys only show as fingerprints
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct AuthOptions {
     pub ips: HashMap<IpAddr, Vec<Username>>,
+    /// Keyed by the bcrypt hash of the password
     pub passwords: HashMap<String, Username>,
     pub commands: HashMap<Username, Vec<UserCommand>>,
     pub tokens: HashMap<String, Username>,
     #[serde(with = "serde_regex")]
     pub domains: Vec<Regex>, // Regex matches for domains
+    #[serde(default)]
+    pub global_commands: Vec<UserCommand>, // Commands run for every user
+    #[serde(default)]
+    pub ip_expiry: HashMap<IpAddr, DateTime<Utc>>,
+    #[serde(default)]
+    pub deny: DenyList, // Checked before any other auth
+    #[serde(default)]
+    pub users: HashMap<Username, User>,
+    #[serde(default)]
+    pub invites: HashMap<String, Invite>, // Keyed by the hash of the invite token
+    #[serde(skip)]
+    verified: VerifiedPasswords,
+}
+
+impl std::fmt::Debug for AuthOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let redact = |credentials: &HashMap<String, Username>| -> Vec<(Secret, Username)> {
+            credentials
+                .iter()
+                .map(|(key, user)| (Secret::from(key.as_str()), user.clone()))
+                .collect()
+        };
+        f.debug_struct("AuthOptions")
+            .field("ips", &self.ips)
+            .field("passwords", &redact(&self.passwords))
+            .field("commands", &self.commands)
+            .field("tokens", &redact(&self.tokens))
+            .field("domains", &self.domains)
+            .field("global_commands", &self.global_commands)
+            .field("ip_expiry", &self.ip_expiry)
+            .field("deny", &self.deny)
+            .field("users", &self.users)
+            .field("invites", &self.invites)
+            .finish()
+    }
 }
 
 impl AuthOptions {
-    pub fn from_string(str: String) -> Self {
-        serde_json::from_str(str.as_str()).unwrap_or_default()
+    pub fn from_string(str: String) -> Result<Self, RauthyError> {
+        let mut auth_options: AuthOptions = serde_json::from_str(str.as_str())?;
+        // Older files kept denied users in the deny list, they are disabled users now
+        for username in std::mem::take(&mut auth_options.deny.users) {
+            auth_options.user_mut(&username).disabled = true;
+        }
+        Ok(auth_options)
+    }
+
+    /// Adds `entries` to the deny list, the users in it are disabled
+    pub fn add_deny(&mut self, entries: DenyList) {
+        for username in entries.users.iter() {
+            self.user_mut(username).disabled = true;
+        }
+        self.deny.extend(entries);
+    }
+
+    /// Removes `entries` from the deny list, the users in it are enabled again
+    pub fn remove_deny(&mut self, entries: &DenyList) {
+        for username in entries.users.iter() {
+            if let Some(user) = self.users.get_mut(username) {
+                user.disabled = false;
+            }
+        }
+        self.deny.remove(entries);
+    }
+
+    /// The deny list with the disabled users as its users
+    pub fn deny_list(&self) -> DenyList {
+        let mut users: Vec<Username> = self
+            .users
+            .iter()
+            .filter(|(_, u)| u.disabled)
+            .map(|(username, _)| username.clone())
+            .collect();
+        users.sort_by_key(|u| u.to_string());
+        DenyList {
+            users,
+            ..self.deny.clone()
+        }
+    }
+
+    /// The user's record, created if they do not have one yet
+    pub fn user_mut(&mut self, username: &Username) -> &mut User {
+        self.users
+            .entry(username.clone())
+            .or_insert_with(|| User::new(Utc::now()))
+    }
+
+    /// Why `username` may not log in at `now`, `None` if they can
+    pub fn user_inactive_reason(
+        &self,
+        username: &Username,
+        now: DateTime<Utc>,
+    ) -> Option<&'static str> {
+        self.users
+            .get(username)
+            .and_then(|u| u.inactive_reason(now))
+    }
+
+    /// `disabled`, `expired` or `active`
+    pub fn user_status(&self, username: &Username, now: DateTime<Utc>) -> &'static str {
+        self.user_inactive_reason(username, now).unwrap_or("active")
+    }
+
+    /// The user whose `email` metadata matches `email`, ignoring case, `None` if several users share it
+    pub fn user_by_email(&self, email: &str) -> Option<&Username> {
+        let email = email.trim();
+        let mut users = self
+            .users
+            .iter()
+            .filter(|(_, u)| {
+                u.metadata
+                    .get("email")
+                    .map(|e| e.eq_ignore_ascii_case(email))
+                    .unwrap_or(false)
+            })
+            .map(|(username, _)| username);
+        // An address shared by several users names none of them
+        match (users.next(), users.next()) {
+            (Some(username), None) => Some(username),
+            _ => None,
+        }
+    }
+
+    pub fn record_login(&mut self, username: &Username, login: Login, keep: usize) {
+        self.user_mut(username).record_login(login, keep);
+    }
+
+    /// The user's record with their credentials counted, `None` if the user is not known
+    pub fn user_details(&self, username: &Username, now: DateTime<Utc>) -> Option<UserDetails> {
+        let count =
+            |users: &HashMap<String, Username>| users.values().filter(|u| *u == username).count();
+        let user = self.users.get(username);
+        let mut ips: Vec<IpAddr> = self
+            .ips
+            .iter()
+            .filter(|(_, users)| users.contains(username))
+            .map(|(ip, _)| *ip)
+            .collect();
+        ips.sort();
+        let details = UserDetails {
+            username: username.clone(),
+            status: self.user_status(username, now),
+            created_at: user.map(|u| u.created_at),
+            last_login_at: user.and_then(|u| u.last_login_at),
+            expires_at: user.and_then(|u| u.expires_at),
+            metadata: user.map(|u| u.metadata.clone()).unwrap_or_default(),
+            passwords: count(&self.passwords),
+            tokens: count(&self.tokens),
+            ips,
+            commands: self.commands.get(username).map(|c| c.len()).unwrap_or(0),
+            totp: user.map(|u| u.totp_secret.is_some()).unwrap_or(false),
+            token_uses: None,
+            logins: user.map(|u| u.logins.clone()).unwrap_or_default(),
+        };
+        let known = user.is_some()
+            || details.passwords > 0
+            || details.tokens > 0
+            || !details.ips.is_empty()
+            || self.commands.contains_key(username);
+        if known {
+            Some(details)
+        } else {
+            None
+        }
+    }
+
+    /// Removes the user's passwords, tokens, IP grants, commands and record, `None` if the user was not found
+    pub fn remove_user(&mut self, username: &Username) -> Option<RemovedUser> {
+        let mut found = self.users.remove(username).is_some();
+        self.invites.retain(|_, i| &i.username != username);
+        found |= self.commands.remove(username).is_some();
+
+        let before = self.passwords.len() + self.tokens.len();
+        self.passwords.retain(|_, u| u != username);
+        self.tokens.retain(|_, u| u != username);
+        found |= before != self.passwords.len() + self.tokens.len();
+
+        let mut removed = RemovedUser::default();
+        for (ip, users) in self.ips.iter_mut() {
+            if users.contains(username) {
+                users.retain(|u| u != username);
+                if users.is_empty() {
+                    removed.removed_ips.push(*ip);
+                } else {
+                    removed.updated_ips.push(*ip);
+                }
+            }
+        }
+        for ip in removed.removed_ips.iter() {
+            self.remove_ip(ip);
+        }
+        found |= !removed.updated_ips.is_empty() || !removed.removed_ips.is_empty();
+
+        if found {
+            Some(removed)
+        } else {
+            None
+        }
     }
 
     pub fn add_password(&mut self, username: String, password: String) {
-        let encoded = base64::encode_config(format!("{}:{}", username, password), base64::URL_SAFE);
-        self.passwords.insert(encoded, username.into());
+        self.passwords
+            .insert(hash_password(&password), username.into());
+    }
+
+    pub fn has_password(&self, username: &Username) -> bool {
+        self.passwords.values().any(|u| u == username)
+    }
+
+    /// Whether `password` is one of the user's passwords,
+    /// followed by a TOTP code valid at `now` if the user enrolled one
+    pub fn check_password(&self, username: &Username, password: &str, now: DateTime<Utc>) -> bool {
+        let totp_secret = self
+            .users
+            .get(username)
+            .and_then(|u| u.totp_secret.as_ref());
+        let password = match totp_secret {
+            Some(secret) => match totp::split_code(password) {
+                Some((password, code)) if totp::verify(secret.expose(), code, now) => password,
+                _ => return false,
+            },
+            None => password,
+        };
+        self.passwords
+            .iter()
+            .filter(|(_, u)| *u == username)
+            .any(|(hash, _)| self.verified.verify(hash, password))
+    }
+
+    pub fn has_legacy_passwords(&self) -> bool {
+        self.passwords.keys().any(|key| !is_password_hash(key))
+    }
+
+    /// Replaces the passwords older versions stored as the base64 of `username:password` with their hash,
+    /// returns how many were replaced
+    pub fn hash_legacy_passwords(&mut self) -> usize {
+        let legacy: Vec<String> = self
+            .passwords
+            .keys()
+            .filter(|key| !is_password_hash(key))
+            .cloned()
+            .collect();
+        for key in legacy.iter() {
+            let username = match self.passwords.remove(key) {
+                Some(username) => username,
+                None => continue,
+            };
+            let prefix = format!("{}:", username);
+            let password = base64::decode_config(key, base64::URL_SAFE)
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .filter(|decoded| decoded.starts_with(&prefix))
+                .map(|decoded| decoded[prefix.len()..].to_string());
+            match password {
+                Some(password) => self.add_password(username.to_string(), password),
+                None => log::warn!("Dropping an unreadable stored password of {}", username),
+            }
+        }
+        legacy.len()
     }
 
     pub fn remove_password_by_user(&mut self, username: String) {
@@ -69,7 +381,69 @@ impl AuthOptions {
         }
     }
 
-    pub fn check_token(&mut self, token: &String) -> Option<Username> {
+    /// Creates an invite for `username` and returns its token, dropping any expired invites
+    pub fn create_invite(
+        &mut self,
+        username: Username,
+        groups: Vec<String>,
+        now: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> String {
+        self.invites.retain(|_, i| !i.is_expired(now));
+        let token = invite::new_token();
+        let invite = Invite {
+            username,
+            created_at: now,
+            expires_at,
+            groups,
+        };
+        self.invites.insert(invite::hash_token(&token), invite);
+        token
+    }
+
+    /// The invite for `token`, `None` if it does not exist or has expired
+    pub fn invite(&self, token: &str, now: DateTime<Utc>) -> Option<&Invite> {
+        self.invites
+            .get(&invite::hash_token(token))
+            .filter(|i| !i.is_expired(now))
+    }
+
+    /// Burns the invite for `token` and sets the invitee's password and the TOTP secret they enrolled.
+    /// `None` if the invite is not valid or the invitee already has a password, which burns it too.
+    pub fn accept_invite(
+        &mut self,
+        token: &str,
+        password: String,
+        now: DateTime<Utc>,
+    ) -> Option<Username> {
+        let invite = self.invites.remove(&invite::hash_token(token))?;
+        if invite.is_expired(now) {
+            return None;
+        }
+        let username = invite.username;
+        if self.has_password(&username) {
+            return None;
+        }
+        self.add_password(username.to_string(), password);
+        let user = self.user_mut(&username);
+        user.totp_secret = Some(invite::totp_secret(token).into());
+        if !invite.groups.is_empty() {
+            let mut groups: Vec<String> = user
+                .metadata
+                .get("groups")
+                .map(|g| g.split(',').map(|g| g.to_string()).collect())
+                .unwrap_or_default();
+            for group in invite.groups {
+                if !groups.contains(&group) {
+                    groups.push(group);
+                }
+            }
+            user.metadata.insert("groups".to_string(), groups.join(","));
+        }
+        Some(username)
+    }
+
+    pub fn check_token(&self, token: &String) -> Option<Username> {
         self.tokens.get(token).map(|u| u.clone())
     }
 
@@ -77,7 +451,7 @@ impl AuthOptions {
         self.tokens.insert(token, username);
     }
 
-    pub fn remove_token(&mut self, token: &String) {
+    pub fn remove_token(&mut self, token: &str) {
         self.tokens.remove(token);
     }
 
@@ -111,9 +485,33 @@ impl AuthOptions {
             self.commands.remove(&username.unwrap());
         } else {
             self.commands.clear();
+            self.global_commands.clear();
         }
     }
 
+    pub fn add_global_command(&mut self, command: UserCommand) {
+        if let Some(command_name) = command.name.clone() {
+            self.global_commands
+                .drain_filter(|c| c.name.contains(&command_name));
+        }
+        self.global_commands.push(command);
+    }
+
+    /// The global commands followed by the user's own commands for `hook`
+    pub fn commands_for(&self, hook: Hook, username: Option<&Username>) -> Vec<UserCommand> {
+        let user_commands = username
+            .and_then(|u| self.commands.get(u))
+            .map(|c| c.iter())
+            .into_iter()
+            .flatten();
+        self.global_commands
+            .iter()
+            .chain(user_commands)
+            .filter(|c| c.hook == hook)
+            .cloned()
+            .collect()
+    }
+
     pub fn add_ip_and_user(&mut self, ip: IpAddr, username: Option<&Username>) {
         let entry = self.ips.entry(ip).or_insert(vec![]);
         if let Some(username) = username {
@@ -123,8 +521,70 @@ impl AuthOptions {
         }
     }
 
+    /// Takes `username` off the grant for `ip`, or every user when `None`, and drops the IP once no user is left.
+    /// Returns the users taken off and whether the IP was dropped, `None` if nothing changed
+    pub fn revoke_ip(
+        &mut self,
+        ip: &IpAddr,
+        username: Option<&Username>,
+    ) -> Option<(Vec<Username>, bool)> {
+        let users = self.ips.get_mut(ip)?;
+        let removed: Vec<Username> = match username {
+            Some(username) if users.contains(username) => {
+                users.retain(|u| u != username);
+                vec![username.clone()]
+            }
+            Some(_) => return None,
+            None => users.drain(..).collect(),
+        };
+        let ip_removed = users.is_empty();
+        if ip_removed {
+            self.remove_ip(ip);
+        }
+        Some((removed, ip_removed))
+    }
+
+    /// IP grants held by users that are disabled or expired at `now`
+    pub fn inactive_grants(&self, now: DateTime<Utc>) -> Vec<(IpAddr, Username)> {
+        self.ips
+            .iter()
+            .flat_map(|(ip, users)| users.iter().map(move |u| (*ip, u.clone())))
+            .filter(|(_, u)| self.user_inactive_reason(u, now).is_some())
+            .collect()
+    }
+
+    /// Takes disabled and expired users off every IP grant and drops the IPs left without a user.
+    /// Returns each IP and user taken off it and whether the IP was dropped.
+    pub fn revoke_inactive(&mut self, now: DateTime<Utc>) -> Vec<(IpAddr, Username, bool)> {
+        self.inactive_grants(now)
+            .into_iter()
+            .filter_map(|(ip, username)| {
+                self.revoke_ip(&ip, Some(&username))
+                    .map(|(_, ip_removed)| (ip, username, ip_removed))
+            })
+            .collect()
+    }
+
+    pub fn clear_ips(&mut self) {
+        self.ips.clear();
+        self.ip_expiry.clear();
+    }
+
     pub fn remove_ip(&mut self, ip: &IpAddr) {
         self.ips.remove(ip);
+        self.ip_expiry.remove(ip);
+    }
+
+    pub fn set_ip_expiry(&mut self, ip: IpAddr, expires_at: DateTime<Utc>) {
+        self.ip_expiry.insert(ip, expires_at);
+    }
+
+    pub fn expired_ips(&self, now: DateTime<Utc>) -> Vec<IpAddr> {
+        self.ip_expiry
+            .iter()
+            .filter(|(ip, expires_at)| expires_at <= &&now && self.ips.contains_key(*ip))
+            .map(|(ip, _)| ip.clone())
+            .collect()
     }
 
     pub fn add_domain_regex(&mut self, regex: Regex) {
@@ -141,3 +601,102 @@ impl AuthOptions {
         self.domains = vec![];
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn debug_hides_passwords_and_tokens() {
+        let mut auth_options = AuthOptions::default();
+        auth_options.add_password("alice".to_string(), "hunter22".to_string());
+        auth_options.add_token("bypass-token".to_string(), "alice".into());
+        let debug = format!("{:?}", auth_options);
+        let encoded = base64::encode_config("alice:hunter22", base64::URL_SAFE);
+        assert!(!debug.contains(&encoded));
+        assert!(!debug.contains("bypass-token"));
+        assert!(debug.contains(&Secret::from("bypass-token").to_string()));
+    }
+
+    #[test]
+    fn legacy_passwords_are_hashed() {
+        let mut auth_options = AuthOptions::default();
+        let alice: Username = "alice".into();
+        let legacy = base64::encode_config("alice:pass:word", base64::URL_SAFE);
+        auth_options.passwords.insert(legacy.clone(), alice.clone());
+        auth_options
+            .passwords
+            .insert("not base64".to_string(), alice.clone());
+        assert!(auth_options.has_legacy_passwords());
+
+        assert_eq!(auth_options.hash_legacy_passwords(), 2);
+        assert!(!auth_options.has_legacy_passwords());
+        assert_eq!(auth_options.passwords.len(), 1);
+        assert!(!auth_options.passwords.contains_key(&legacy));
+        let now = Utc::now();
+        assert!(auth_options.check_password(&alice, "pass:word", now));
+        // Verified again from the cache
+        assert!(auth_options.check_password(&alice, "pass:word", now));
+        assert!(!auth_options.check_password(&alice, "pass", now));
+        assert!(!auth_options.check_password(&"bob".into(), "pass:word", now));
+    }
+
+    #[test]
+    fn accept_invite_sets_the_password_once() {
+        let now = Utc::now();
+        let expires_at = now + chrono::Duration::hours(1);
+        let mut auth_options = AuthOptions::default();
+        let bob: Username = "bob".into();
+        let groups = vec!["ops".to_string()];
+        let token = auth_options.create_invite(bob.clone(), groups, now, expires_at);
+        assert_eq!(
+            auth_options.accept_invite(&token, "password1".to_string(), now),
+            Some(bob.clone())
+        );
+        assert!(auth_options.has_password(&bob));
+        assert_eq!(auth_options.users[&bob].metadata["groups"], "ops");
+        let totp_secret = auth_options.users[&bob].totp_secret.clone();
+        assert_eq!(totp_secret, Some(invite::totp_secret(&token).into()));
+        // Burned
+        assert_eq!(
+            auth_options.accept_invite(&token, "password2".to_string(), now),
+            None
+        );
+
+        let expired = auth_options.create_invite(bob.clone(), vec![], now, now);
+        assert_eq!(
+            auth_options.accept_invite(&expired, "password2".to_string(), now),
+            None
+        );
+
+        // A user that already has a password can't have it replaced through an invite
+        let token = auth_options.create_invite(bob.clone(), vec![], now, expires_at);
+        assert_eq!(
+            auth_options.accept_invite(&token, "password2".to_string(), now),
+            None
+        );
+        assert_eq!(auth_options.passwords.len(), 1);
+        assert_eq!(auth_options.users[&bob].totp_secret, totp_secret);
+        assert!(auth_options.invite(&token, now).is_none());
+    }
+
+    #[test]
+    fn check_password_requires_the_totp_code_once_enrolled() {
+        // The RFC 6238 test secret, 287082 is its code at 59 seconds past the epoch
+        let now = Utc.timestamp_opt(59, 0).unwrap();
+        let mut auth_options = AuthOptions::default();
+        let alice: Username = "alice".into();
+        auth_options.add_password("alice".to_string(), "hunter22".to_string());
+        assert!(auth_options.check_password(&alice, "hunter22", now));
+
+        let secret = totp::encode_secret(b"12345678901234567890");
+        auth_options.user_mut(&alice).totp_secret = Some(secret.into());
+        assert!(auth_options.check_password(&alice, "hunter22287082", now));
+        assert!(!auth_options.check_password(&alice, "hunter22", now));
+        assert!(!auth_options.check_password(&alice, "hunter22123456", now));
+        assert!(!auth_options.check_password(&alice, "wrong123287082", now));
+        let later = now + chrono::Duration::minutes(5);
+        assert!(!auth_options.check_password(&alice, "hunter22287082", later));
+    }
+}
@@ -1,24 +1,133 @@
+use crate::config::auth_options::Username;
 use crate::error::RauthyError;
 use serde::export::Formatter;
 use serde::{Deserialize, Serialize};
-use std::process::{Command, Output};
+use std::net::IpAddr;
+use std::process::Output;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// The events user commands can be attached to
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Hash, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Hook {
+    OnLogin,
+    OnIpAdded,
+    OnIpExpired,
+    OnIpRemoved,
+    OnAuthFailure,
+    OnLockout,
+    OnLogout,
+}
+
+impl Hook {
+    pub const NAMES: &'static [&'static str] = &[
+        "on_login",
+        "on_ip_added",
+        "on_ip_expired",
+        "on_ip_removed",
+        "on_auth_failure",
+        "on_lockout",
+        "on_logout",
+    ];
+}
+
+impl Default for Hook {
+    fn default() -> Self {
+        Hook::OnLogin
+    }
+}
+
+impl FromStr for Hook {
+    type Err = RauthyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .map_err(|_| RauthyError::ConfigError(format!("Unknown hook {}", s)))
+    }
+}
+
+impl std::fmt::Display for Hook {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = serde_json::to_value(self)
+            .ok()
+            .and_then(|v| v.as_str().map(|s| s.to_string()))
+            .unwrap_or_default();
+        write!(f, "{}", name)
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct UserCommand {
     pub name: Option<String>,
     pub path: Option<String>,
     pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Run `command` with `sh -c`, `args` become `$1`, `$2`, ...
+    #[serde(default)]
+    pub shell: bool,
+    /// Seconds before the command is killed, falls back to `COMMAND_TIMEOUT`
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub hook: Hook,
+}
+
+/// Details of the request that triggered a command, exposed to it as `RAUTHY_*` variables
+#[derive(Debug, Clone, Default)]
+pub struct CommandContext {
+    pub hook: Hook,
+    pub user: Username,
+    pub ip: Option<IpAddr>,
+    pub auth_type: String,
+    pub host: Option<String>,
+    pub path: String,
+}
+
+impl CommandContext {
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("RAUTHY_HOOK", self.hook.to_string()),
+            ("RAUTHY_USER", self.user.to_string()),
+            (
+                "RAUTHY_IP",
+                self.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            ),
+            ("RAUTHY_AUTH_TYPE", self.auth_type.clone()),
+            ("RAUTHY_HOST", self.host.clone().unwrap_or_default()),
+            ("RAUTHY_PATH", self.path.clone()),
+        ]
+    }
 }
 
 impl UserCommand {
-    pub fn run(&self) -> Result<Output, RauthyError> {
-        let mut command = Command::new(self.command.clone());
+    pub async fn run(
+        &self,
+        context: &CommandContext,
+        default_timeout: Duration,
+    ) -> Result<Output, RauthyError> {
+        let mut command = if self.shell {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(self.command.clone()).arg("rauthy");
+            command
+        } else {
+            Command::new(self.command.clone())
+        };
+        command.args(self.args.iter()).envs(context.env());
         if let Some(current_dir) = self.path.clone() {
             command.current_dir(current_dir);
         }
-        Ok(command
-            .output()
-            .map_err(|e| RauthyError::CommandError(e.to_string()))?)
+        // Dropping the output future on timeout kills the child
+        command.kill_on_drop(true);
+        let timeout = self
+            .timeout
+            .map(Duration::from_secs)
+            .unwrap_or(default_timeout);
+        match tokio::time::timeout(timeout, command.output()).await {
+            Ok(output) => Ok(output.map_err(|e| RauthyError::CommandError(e.to_string()))?),
+            Err(_) => Err(RauthyError::CommandTimeout(timeout)),
+        }
     }
 }
 
@@ -26,9 +135,17 @@ impl std::fmt::Display for UserCommand {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "cd {} && {}",
-            self.path.as_ref().unwrap_or(&".".to_string()),
-            self.command
-        )
+            "cd {} && ",
+            self.path.as_ref().unwrap_or(&".".to_string())
+        )?;
+        if self.shell {
+            write!(f, "sh -c {:?}", self.command)?;
+        } else {
+            write!(f, "{}", self.command)?;
+        }
+        for arg in self.args.iter() {
+            write!(f, " {:?}", arg)?;
+        }
+        Ok(())
     }
 }
@@ -1,17 +1,62 @@
-use crate::config::auth_options::AuthOptions;
+use crate::config::auth_file::{AuthFile, AuthFileLock};
+use crate::config::auth_options::{AuthOptions, Username};
+use crate::config::command::Hook;
+use crate::config::password_policy::PasswordPolicy;
+use crate::config::redis::RedisStore;
+use crate::config::secret::Secret;
+use crate::config::smtp::{SmtpConfig, SmtpSecurity};
+use crate::config::sqlite::SqliteStore;
 use crate::error::RauthyError;
-use std::net::SocketAddr;
-use tokio::fs::File;
-use tokio::prelude::*; // for write_all()
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub listen: SocketAddr,
     pub message: String,
     pub auth_file: Option<String>,
+    pub auth_file_backups: usize,
+    /// Held by CLI commands from loading the auth file until they are done writing it
+    pub auth_file_lock: Option<Arc<AuthFileLock>>,
+    /// SHA-256 of what this process last wrote to the auth file, so the watcher can skip its own writes
+    pub auth_file_written: Arc<std::sync::Mutex<Option<String>>>,
+    pub database: Option<String>,
+    pub redis_url: Option<String>,
+    pub redis_prefix: String,
     pub auth_options: AuthOptions,
     pub include_user_header: bool,
     pub ignore_ip: bool,
+    pub persist_interval: Duration,
+    pub persist_max_pending: usize,
+    pub command_timeout: Duration,
+    pub command_concurrency: usize,
+    pub ip_grant_ttl: Option<Duration>,
+    pub webhook_urls: Vec<String>,
+    pub webhook_secret: Option<Secret>,
+    pub webhook_events: Vec<Hook>,
+    pub webhook_retries: u32,
+    pub webhook_timeout: Duration,
+    pub webhook_queue_size: usize,
+    pub audit_log: Option<String>,
+    pub audit_log_max_size: u64,
+    pub audit_log_backups: usize,
+    pub otel_endpoint: Option<String>,
+    pub login_history_size: usize,
+    pub logout_redirect: Option<String>,
+    pub password_policy: PasswordPolicy,
+    pub public_url: Option<String>,
+    pub smtp: Option<SmtpConfig>,
+    pub login_link_secret: Option<Secret>,
+    /// Required in `x-admin-token` by the administration endpoints when set
+    pub admin_token: Option<Secret>,
+    pub login_link_ttl: Duration,
+    pub login_redirect: Option<String>,
+    /// Failures before an IP or user is locked out, `0` disables the lockout
+    pub lockout_threshold: u32,
+    pub lockout_window: Duration,
+    pub lockout_duration: Duration,
 }
 
 impl Config {
@@ -23,6 +68,13 @@ impl Config {
             .unwrap();
         let message = dotenv::var("BASIC_AUTH_MESSAGE").unwrap_or("Rauthy says no!".to_string());
         let auth_file = dotenv::var("AUTH_FILE").ok();
+        let auth_file_backups = dotenv::var("AUTH_FILE_BACKUPS")
+            .ok()
+            .map(|b| b.parse().unwrap_or(3))
+            .unwrap_or(3);
+        let database = dotenv::var("AUTH_DATABASE").ok().filter(|d| !d.is_empty());
+        let redis_url = dotenv::var("REDIS_URL").ok().filter(|r| !r.is_empty());
+        let redis_prefix = dotenv::var("REDIS_PREFIX").unwrap_or("rauthy".to_string());
         let include_user_header = dotenv::var("INCLUDE_USER_HEADER")
             .ok()
             .map(|b| b.parse().unwrap_or(false))
@@ -31,34 +83,324 @@ impl Config {
             .ok()
             .map(|b| b.parse().unwrap_or(false))
             .unwrap_or_else(|| false);
+        let persist_interval = dotenv::var("PERSIST_INTERVAL")
+            .ok()
+            .map(|s| s.parse().unwrap_or(5))
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| Duration::from_secs(5));
+        let persist_max_pending = dotenv::var("PERSIST_MAX_PENDING")
+            .ok()
+            .map(|s| s.parse().unwrap_or(100))
+            .unwrap_or(100);
+        let command_timeout = dotenv::var("COMMAND_TIMEOUT")
+            .ok()
+            .map(|s| s.parse().unwrap_or(30))
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| Duration::from_secs(30));
+        let command_concurrency = dotenv::var("COMMAND_CONCURRENCY")
+            .ok()
+            .map(|s| s.parse().unwrap_or(4))
+            .unwrap_or(4);
+        let ip_grant_ttl = dotenv::var("IP_GRANT_TTL")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(Duration::from_secs);
+        let webhook_urls = dotenv::var("WEBHOOK_URLS")
+            .map(|s| {
+                s.split(',')
+                    .map(|u| u.trim().to_string())
+                    .filter(|u| !u.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let webhook_secret = dotenv::var("WEBHOOK_SECRET")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(Secret::from);
+        let webhook_events = match dotenv::var("WEBHOOK_EVENTS") {
+            Ok(events) => events
+                .split(',')
+                .map(|e| e.trim())
+                .filter(|e| !e.is_empty())
+                .map(|e| e.parse())
+                .collect::<Result<Vec<Hook>, RauthyError>>()?,
+            Err(_) => vec![
+                Hook::OnLogin,
+                Hook::OnAuthFailure,
+                Hook::OnIpAdded,
+                Hook::OnLockout,
+            ],
+        };
+        let webhook_retries = dotenv::var("WEBHOOK_RETRIES")
+            .ok()
+            .map(|s| s.parse().unwrap_or(3))
+            .unwrap_or(3);
+        let webhook_timeout = dotenv::var("WEBHOOK_TIMEOUT")
+            .ok()
+            .map(|s| s.parse().unwrap_or(10))
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| Duration::from_secs(10));
+        let webhook_queue_size = dotenv::var("WEBHOOK_QUEUE_SIZE")
+            .ok()
+            .map(|s| s.parse().unwrap_or(100))
+            .unwrap_or(100);
+        let audit_log = dotenv::var("AUDIT_LOG").ok().filter(|a| !a.is_empty());
+        let audit_log_max_size = dotenv::var("AUDIT_LOG_MAX_SIZE")
+            .ok()
+            .map(|s| s.parse().unwrap_or(10 * 1024 * 1024))
+            .unwrap_or(10 * 1024 * 1024);
+        let audit_log_backups = dotenv::var("AUDIT_LOG_BACKUPS")
+            .ok()
+            .map(|s| s.parse().unwrap_or(5))
+            .unwrap_or(5);
+        let otel_endpoint = dotenv::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .ok()
+            .filter(|e| !e.is_empty());
+        let login_history_size = dotenv::var("LOGIN_HISTORY_SIZE")
+            .ok()
+            .map(|s| s.parse().unwrap_or(20))
+            .unwrap_or(20);
+        let logout_redirect = dotenv::var("LOGOUT_REDIRECT_URL")
+            .ok()
+            .filter(|u| !u.is_empty());
+        let password_policy = PasswordPolicy {
+            min_length: dotenv::var("PASSWORD_MIN_LENGTH")
+                .ok()
+                .map(|s| s.parse().unwrap_or(8))
+                .unwrap_or(8),
+            breach_list: dotenv::var("PASSWORD_BREACH_LIST")
+                .ok()
+                .filter(|p| !p.is_empty()),
+        };
+        let public_url = dotenv::var("PUBLIC_URL")
+            .ok()
+            .filter(|u| !u.is_empty())
+            .map(|u| u.trim_end_matches('/').to_string());
+        let smtp = match dotenv::var("SMTP_HOST").ok().filter(|h| !h.is_empty()) {
+            Some(host) => {
+                let security: SmtpSecurity = dotenv::var("SMTP_SECURITY")
+                    .unwrap_or("starttls".to_string())
+                    .parse()?;
+                let port = dotenv::var("SMTP_PORT")
+                    .ok()
+                    .and_then(|p| p.parse().ok())
+                    .unwrap_or_else(|| security.default_port());
+                let from = dotenv::var("SMTP_FROM").map_err(|_| {
+                    RauthyError::ConfigError("SMTP_FROM must be set with SMTP_HOST".to_string())
+                })?;
+                Some(SmtpConfig {
+                    host,
+                    port,
+                    security,
+                    username: dotenv::var("SMTP_USERNAME").ok().filter(|u| !u.is_empty()),
+                    password: dotenv::var("SMTP_PASSWORD")
+                        .ok()
+                        .filter(|p| !p.is_empty())
+                        .map(Secret::from),
+                    from,
+                })
+            }
+            None => None,
+        };
+        let login_link_secret = dotenv::var("LOGIN_LINK_SECRET")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(Secret::from);
+        let admin_token = dotenv::var("ADMIN_TOKEN")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(Secret::from);
+        let login_link_ttl = dotenv::var("LOGIN_LINK_TTL")
+            .ok()
+            .map(|s| s.parse().unwrap_or(900))
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| Duration::from_secs(900));
+        let login_redirect = dotenv::var("LOGIN_REDIRECT_URL")
+            .ok()
+            .filter(|u| !u.is_empty());
+
+        let lockout_threshold = dotenv::var("LOCKOUT_THRESHOLD")
+            .ok()
+            .map(|s| s.parse().unwrap_or(10))
+            .unwrap_or(10);
+        let lockout_window = dotenv::var("LOCKOUT_WINDOW")
+            .ok()
+            .map(|s| s.parse().unwrap_or(300))
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| Duration::from_secs(300));
+        let lockout_duration = dotenv::var("LOCKOUT_DURATION")
+            .ok()
+            .map(|s| s.parse().unwrap_or(900))
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| Duration::from_secs(900));
 
-        let auth_options = Self::load_file(auth_file.clone()).await.unwrap_or_default();
-        Ok(Config {
+        let auth_options = if let Some(database) = database.clone() {
+            Self::load_database(database).await?
+        } else {
+            Self::load_file(auth_file.clone(), None).await.map_err(|e| {
+                RauthyError::ConfigError(format!(
+                    "Unable to load auth file {}: {}",
+                    auth_file.clone().unwrap_or_default(),
+                    e
+                ))
+            })?
+        };
+        let mut config = Config {
             listen,
             message,
             auth_file,
+            auth_file_backups,
+            auth_file_lock: None,
+            auth_file_written: Arc::default(),
+            database,
+            redis_url,
+            redis_prefix,
             auth_options,
             include_user_header,
             ignore_ip,
-        })
+            persist_interval,
+            persist_max_pending,
+            command_timeout,
+            command_concurrency,
+            ip_grant_ttl,
+            webhook_urls,
+            webhook_secret,
+            webhook_events,
+            webhook_retries,
+            webhook_timeout,
+            webhook_queue_size,
+            audit_log,
+            audit_log_max_size,
+            audit_log_backups,
+            otel_endpoint,
+            login_history_size,
+            logout_redirect,
+            password_policy,
+            public_url,
+            smtp,
+            login_link_secret,
+            admin_token,
+            login_link_ttl,
+            login_redirect,
+            lockout_threshold,
+            lockout_window,
+            lockout_duration,
+        };
+        if config.auth_options.has_legacy_passwords() {
+            config.hash_legacy_passwords().await?;
+        }
+        Ok(config)
+    }
+
+    /// Replaces passwords stored by older versions with their hash and writes them back,
+    /// holding the auth file lock so no other change is overwritten
+    async fn hash_legacy_passwords(&mut self) -> Result<(), RauthyError> {
+        let held = self.auth_file_lock.is_some();
+        if !held {
+            self.lock_auth_file().await?;
+        }
+        let hashed = self.auth_options.hash_legacy_passwords();
+        if hashed > 0 {
+            log::info!("Hashed {} passwords stored by an older version", hashed);
+            self.write().await?;
+        }
+        if !held {
+            self.auth_file_lock = None;
+        }
+        Ok(())
+    }
+
+    /// Locks the auth file until `self` is dropped and reloads it under the lock,
+    /// so nothing else can write between loading and writing it back
+    pub async fn lock_auth_file(&mut self) -> Result<(), RauthyError> {
+        let auth_file = match (self.database.as_ref(), self.auth_file.clone()) {
+            (None, Some(auth_file)) => auth_file,
+            _ => return Ok(()),
+        };
+        let file = AuthFile::new(auth_file.clone(), 0);
+        let lock = tokio::task::spawn_blocking(move || file.lock_exclusive()).await??;
+        self.auth_file_lock = Some(Arc::new(lock));
+        self.auth_options = Self::load_file(Some(auth_file), self.auth_file_lock.clone()).await?;
+        Ok(())
     }
 
-    pub async fn load_file(auth_file: Option<String>) -> Result<AuthOptions, RauthyError> {
+    pub async fn load_file(
+        auth_file: Option<String>,
+        lock: Option<Arc<AuthFileLock>>,
+    ) -> Result<AuthOptions, RauthyError> {
         if let Some(auth_file) = auth_file {
-            let contents = tokio::fs::read_to_string(auth_file.clone()).await?;
-            Ok(AuthOptions::from_string(contents))
+            let file = AuthFile::new(auth_file, 0).with_lock(lock);
+            match tokio::task::spawn_blocking(move || file.read()).await?? {
+                Some(contents) => AuthOptions::from_string(contents),
+                None => Ok(AuthOptions::default()),
+            }
         } else {
             Ok(AuthOptions::default())
         }
     }
 
+    pub async fn connect_redis(&self) -> Result<Option<RedisStore>, RauthyError> {
+        if let Some(redis_url) = self.redis_url.clone() {
+            Ok(Some(
+                RedisStore::connect(redis_url, self.redis_prefix.clone()).await?,
+            ))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub async fn load_database(database: String) -> Result<AuthOptions, RauthyError> {
+        tokio::task::spawn_blocking(move || SqliteStore::new(database).load()).await?
+    }
+
     pub async fn write(&self) -> Result<(), RauthyError> {
-        if let Some(auth_file) = self.auth_file.clone() {
-            let mut file = File::create(auth_file.clone()).await?;
-            let json = serde_json::to_string(&self.auth_options)?;
-            file.write_all(json.as_bytes())
-                .await
-                .map_err(|_| RauthyError::ConfigError(format!("Error writing to {}", auth_file)))?;
+        self.write_options(&self.auth_options).await
+    }
+
+    /// Persists the given IPs and users of `auth_options`.
+    /// Only SQLite can write single rows, the auth file is always rewritten as a whole.
+    pub async fn write_changes(
+        &self,
+        auth_options: &AuthOptions,
+        ips: Vec<IpAddr>,
+        users: Vec<Username>,
+    ) -> Result<(), RauthyError> {
+        match self.database.clone() {
+            Some(database) => {
+                let store = SqliteStore::new(database.clone());
+                let auth_options = auth_options.clone();
+                tokio::task::spawn_blocking(move || {
+                    store.save_changes(&auth_options, &ips, &users)
+                })
+                .await??;
+                log::debug!("Successfully wrote changes to {}", database);
+                Ok(())
+            }
+            None => self.write_options(auth_options).await,
+        }
+    }
+
+    pub async fn write_options(&self, auth_options: &AuthOptions) -> Result<(), RauthyError> {
+        if let Some(database) = self.database.clone() {
+            let store = SqliteStore::new(database.clone());
+            let auth_options = auth_options.clone();
+            tokio::task::spawn_blocking(move || store.save(&auth_options)).await??;
+            log::debug!("Successfully wrote {}", database)
+        } else if let Some(auth_file) = self.auth_file.clone() {
+            let file = AuthFile::new(auth_file.clone(), self.auth_file_backups)
+                .with_lock(self.auth_file_lock.clone());
+            let json = serde_json::to_string(auth_options)?;
+            let hash = hex::encode(Sha256::digest(json.as_bytes()));
+            // Recorded before writing, the watcher may see the new file before the write returns
+            let previous = self.auth_file_written.lock().unwrap().replace(hash);
+            let result = tokio::task::spawn_blocking(move || file.write(json.as_str())).await?;
+            if let Err(e) = result {
+                *self.auth_file_written.lock().unwrap() = previous;
+                return Err(RauthyError::ConfigError(format!(
+                    "Error writing to {}: {}",
+                    auth_file, e
+                )));
+            }
             log::debug!("Successfully wrote {}", auth_file)
         } else {
             log::trace!("No config file configured")
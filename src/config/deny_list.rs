@@ -0,0 +1,79 @@
+use crate::config::auth_options::Username;
+use crate::config::secret::Secret;
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// Addresses and tokens that are refused before any other auth check.
+/// `users` is only used to add and list entries, denying a user disables their record.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct DenyList {
+    #[serde(default)]
+    pub ips: Vec<IpNetwork>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<Username>,
+    #[serde(default)]
+    pub tokens: Vec<Secret>,
+}
+
+impl DenyList {
+    pub fn is_empty(&self) -> bool {
+        self.ips.is_empty() && self.users.is_empty() && self.tokens.is_empty()
+    }
+
+    pub fn denies_ip(&self, ip: &IpAddr) -> bool {
+        self.ips.iter().any(|network| network.contains(*ip))
+    }
+
+    pub fn denies_token(&self, token: &str) -> bool {
+        self.tokens.iter().any(|t| t.expose() == token)
+    }
+
+    pub fn add_ip(&mut self, network: IpNetwork) {
+        if !self.ips.contains(&network) {
+            self.ips.push(network);
+        }
+    }
+
+    pub fn remove_ip(&mut self, network: &IpNetwork) {
+        self.ips.retain(|n| n != network);
+    }
+
+    pub fn add_token(&mut self, token: Secret) {
+        if !self.denies_token(token.expose()) {
+            self.tokens.push(token);
+        }
+    }
+
+    pub fn remove_token(&mut self, token: &str) {
+        self.tokens.retain(|t| t.expose() != token);
+    }
+
+    /// Every entry as `(kind, value)` where kind is `ip` or `token`, tokens are exposed
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        self.ips
+            .iter()
+            .map(|n| ("ip", n.to_string()))
+            .chain(
+                self.tokens
+                    .iter()
+                    .map(|t| ("token", t.expose().to_string())),
+            )
+            .collect()
+    }
+
+    /// Adds the IPs and tokens of `other`
+    pub fn extend(&mut self, other: DenyList) {
+        other.ips.into_iter().for_each(|n| self.add_ip(n));
+        other.tokens.into_iter().for_each(|t| self.add_token(t));
+    }
+
+    /// Removes the IPs and tokens of `other`
+    pub fn remove(&mut self, other: &DenyList) {
+        other.ips.iter().for_each(|n| self.remove_ip(n));
+        other
+            .tokens
+            .iter()
+            .for_each(|t| self.remove_token(t.expose()));
+    }
+}
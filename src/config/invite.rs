@@ -0,0 +1,47 @@
+use crate::config::auth_options::Username;
+use crate::config::totp;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// A pending invitation, stored under the hash of its token so the store can't be used to redeem it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Invite {
+    pub username: Username,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Added to the user's `groups` metadata once the invite is accepted
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl Invite {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+}
+
+/// A new random invite token, only shown once when the invite is created
+pub fn new_token() -> String {
+    format!(
+        "{}{}",
+        Uuid::new_v4().to_simple(),
+        Uuid::new_v4().to_simple()
+    )
+}
+
+/// The key an invite is stored under
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// The TOTP secret the invitee enrolls with, derived from the token so the invite page and the form it posts
+/// agree on it without the store holding it before the invite is accepted
+pub fn totp_secret(token: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(token.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(b"totp");
+    totp::encode_secret(&mac.finalize().into_bytes()[..20])
+}
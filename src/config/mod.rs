@@ -1,3 +1,13 @@
+pub mod auth_file;
 pub mod auth_options;
 pub mod command;
 pub mod config;
+pub mod deny_list;
+pub mod invite;
+pub mod password_policy;
+pub mod redis;
+pub mod secret;
+pub mod smtp;
+pub mod sqlite;
+pub mod totp;
+pub mod user;
@@ -0,0 +1,52 @@
+use crate::config::secret::Secret;
+use crate::error::RauthyError;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// Rules a new password must meet when users change it themselves
+#[derive(Clone, Debug)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// A file of known breached passwords, one per line
+    pub breach_list: Option<String>,
+}
+
+impl PasswordPolicy {
+    /// Why `password` is refused for `username`, `None` if it is acceptable
+    pub fn violation(
+        &self,
+        username: &str,
+        password: &Secret,
+    ) -> Result<Option<String>, RauthyError> {
+        let value = password.expose();
+        if value.chars().count() < self.min_length {
+            return Ok(Some(format!(
+                "Passwords must be at least {} characters",
+                self.min_length
+            )));
+        }
+        if value.eq_ignore_ascii_case(username) {
+            return Ok(Some("Passwords must not match the username".to_string()));
+        }
+        if self.is_breached(value)? {
+            return Ok(Some(
+                "This password has appeared in a data breach".to_string(),
+            ));
+        }
+        Ok(None)
+    }
+
+    fn is_breached(&self, password: &str) -> Result<bool, RauthyError> {
+        let path = match self.breach_list.as_ref() {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+        // Read line by line, breach lists can be far too large to keep in memory
+        for line in BufReader::new(File::open(path)?).lines() {
+            if line?.trim_end_matches('\r') == password {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
@@ -0,0 +1,307 @@
+use crate::config::auth_options::Username;
+use crate::error::RauthyError;
+use futures::StreamExt;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Script};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Published to every replica when an IP grant changes, the receivers reload the IP from redis.
+/// `ip` of `None` means all grants changed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IpInvalidation {
+    pub ip: Option<IpAddr>,
+}
+
+/// A change to the IP grants shared between replicas.
+/// Each is applied atomically in redis, so replicas changing the same IP at once don't overwrite each other.
+#[derive(Debug, Clone)]
+pub enum IpChange {
+    /// Adds the user to the IP, or grants the IP without a user when `None`
+    Grant(IpAddr, Option<Username>),
+    /// Takes the user off the IP, the IP is removed once no user is left
+    Revoke(IpAddr, Username),
+    Remove(IpAddr),
+}
+
+/// Users are kept in one set per IP, the empty member marks an IP granted without a user
+const GRANT_SCRIPT: &str = r"
+if ARGV[2] == '' then
+    if redis.call('EXISTS', KEYS[1]) == 0 then
+        redis.call('SADD', KEYS[1], '')
+    end
+else
+    redis.call('SREM', KEYS[1], '')
+    redis.call('SADD', KEYS[1], ARGV[2])
+end
+redis.call('SADD', KEYS[2], ARGV[1])
+";
+
+const REVOKE_SCRIPT: &str = r"
+redis.call('SREM', KEYS[1], ARGV[2])
+if redis.call('SCARD', KEYS[1]) == 0 then
+    redis.call('SREM', KEYS[2], ARGV[1])
+end
+";
+
+const CLEAR_SCRIPT: &str = r"
+for _, ip in ipairs(redis.call('SMEMBERS', KEYS[1])) do
+    redis.call('DEL', ARGV[1] .. ip)
+end
+redis.call('DEL', KEYS[1])
+";
+
+/// Counts a failure in KEYS[1] for ARGV[1] seconds and sets the lockout KEYS[2] for ARGV[3] seconds
+/// once ARGV[2] failures are reached, returns 1 if this failure locked the key out
+const FAILURE_SCRIPT: &str = r"
+local failures = redis.call('INCR', KEYS[1])
+if failures == 1 then
+    redis.call('EXPIRE', KEYS[1], ARGV[1])
+end
+if failures >= tonumber(ARGV[2]) then
+    redis.call('DEL', KEYS[1])
+    redis.call('SET', KEYS[2], 1, 'EX', ARGV[3])
+    return 1
+end
+return 0
+";
+
+#[derive(Clone)]
+pub struct RedisStore {
+    client: redis::Client,
+    connection: MultiplexedConnection,
+    prefix: String,
+}
+
+impl RedisStore {
+    pub async fn connect(url: String, prefix: String) -> Result<Self, RauthyError> {
+        let client = redis::Client::open(url.as_str())?;
+        let connection = client.get_multiplexed_tokio_connection().await?;
+        let store = Self {
+            client,
+            connection,
+            prefix,
+        };
+        store.migrate_ip_hash().await?;
+        Ok(store)
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}:{}", self.prefix, name)
+    }
+
+    /// The set of granted IPs
+    fn index_key(&self) -> String {
+        self.key("ip_index")
+    }
+
+    /// The set of users granted `ip`
+    fn ip_key(&self, ip: &str) -> String {
+        self.key(&format!("ip:{}", ip))
+    }
+
+    /// Moves grants stored by older versions as one JSON list per IP in the `ips` hash into the per-IP sets
+    async fn migrate_ip_hash(&self) -> Result<(), RauthyError> {
+        let mut conn = self.connection.clone();
+        let stored: HashMap<String, String> = conn.hgetall(self.key("ips")).await?;
+        if stored.is_empty() {
+            return Ok(());
+        }
+        for (ip, users) in stored {
+            let ip = match ip.parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(e) => {
+                    log::warn!("Skipping invalid IP {} stored in redis: {}", ip, e);
+                    continue;
+                }
+            };
+            let users: Vec<Username> = serde_json::from_str(users.as_str())?;
+            self.change(&IpChange::Grant(ip, None)).await?;
+            for user in users {
+                self.change(&IpChange::Grant(ip, Some(user))).await?;
+            }
+        }
+        let _: () = conn.del(self.key("ips")).await?;
+        log::info!("Migrated IP grants in redis to per-IP sets");
+        Ok(())
+    }
+
+    pub async fn ping(&self) -> Result<(), RauthyError> {
+        let mut conn = self.connection.clone();
+        let _: String = redis::cmd("PING").query_async(&mut conn).await?;
+        Ok(())
+    }
+
+    pub async fn load_ips(&self) -> Result<HashMap<IpAddr, Vec<Username>>, RauthyError> {
+        let mut conn = self.connection.clone();
+        let stored: Vec<String> = conn.smembers(self.index_key()).await?;
+        let mut ips = HashMap::new();
+        for ip in stored {
+            match ip.parse::<IpAddr>() {
+                Ok(ip) => {
+                    if let Some(users) = self.load_ip(ip).await? {
+                        ips.insert(ip, users);
+                    }
+                }
+                Err(e) => log::warn!("Skipping invalid IP {} stored in redis: {}", ip, e),
+            }
+        }
+        Ok(ips)
+    }
+
+    /// The users granted `ip`, `None` if it isn't granted
+    pub async fn load_ip(&self, ip: IpAddr) -> Result<Option<Vec<Username>>, RauthyError> {
+        let mut conn = self.connection.clone();
+        let members: Vec<String> = conn.smembers(self.ip_key(&ip.to_string())).await?;
+        if members.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(
+            members
+                .into_iter()
+                .filter(|u| !u.is_empty())
+                .map(Username::from)
+                .collect(),
+        ))
+    }
+
+    pub async fn change(&self, change: &IpChange) -> Result<(), RauthyError> {
+        let mut conn = self.connection.clone();
+        let ip = match change {
+            IpChange::Grant(ip, username) => {
+                let username = username.as_ref().map(|u| u.to_string()).unwrap_or_default();
+                let _: () = Script::new(GRANT_SCRIPT)
+                    .key(self.ip_key(&ip.to_string()))
+                    .key(self.index_key())
+                    .arg(ip.to_string())
+                    .arg(username)
+                    .invoke_async(&mut conn)
+                    .await?;
+                ip
+            }
+            IpChange::Revoke(ip, username) => {
+                let _: () = Script::new(REVOKE_SCRIPT)
+                    .key(self.ip_key(&ip.to_string()))
+                    .key(self.index_key())
+                    .arg(ip.to_string())
+                    .arg(username.to_string())
+                    .invoke_async(&mut conn)
+                    .await?;
+                ip
+            }
+            IpChange::Remove(ip) => {
+                let _: () = redis::pipe()
+                    .atomic()
+                    .del(self.ip_key(&ip.to_string()))
+                    .srem(self.index_key(), ip.to_string())
+                    .query_async(&mut conn)
+                    .await?;
+                ip
+            }
+        };
+        self.publish(IpInvalidation { ip: Some(*ip) }).await
+    }
+
+    pub async fn clear_ips(&self) -> Result<(), RauthyError> {
+        let mut conn = self.connection.clone();
+        let _: () = Script::new(CLEAR_SCRIPT)
+            .key(self.index_key())
+            .arg(self.ip_key(""))
+            .invoke_async(&mut conn)
+            .await?;
+        self.publish(IpInvalidation { ip: None }).await
+    }
+
+    /// Counts bypass token authentications per user
+    pub async fn record_token_usage(&self, username: &Username) -> Result<(), RauthyError> {
+        let mut conn = self.connection.clone();
+        let _: () = conn
+            .hincr(self.key("token_usage"), username.to_string(), 1)
+            .await?;
+        Ok(())
+    }
+
+    /// How often `username` authenticated with a bypass token, counted across all replicas
+    pub async fn token_usage(&self, username: &Username) -> Result<u64, RauthyError> {
+        let mut conn = self.connection.clone();
+        let uses: Option<u64> = conn
+            .hget(self.key("token_usage"), username.to_string())
+            .await?;
+        Ok(uses.unwrap_or(0))
+    }
+
+    /// Counts a failure of `key`, returns whether it locked the key out
+    pub async fn record_failure(
+        &self,
+        key: &str,
+        threshold: u32,
+        window: Duration,
+        duration: Duration,
+    ) -> Result<bool, RauthyError> {
+        let mut conn = self.connection.clone();
+        let locked: i64 = Script::new(FAILURE_SCRIPT)
+            .key(self.key(&format!("failures:{}", key)))
+            .key(self.key(&format!("lockout:{}", key)))
+            .arg(window.as_secs().max(1))
+            .arg(threshold)
+            .arg(duration.as_secs().max(1))
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(locked == 1)
+    }
+
+    /// Marks `key` as used for `ttl`, returns `false` if another replica already did
+    pub async fn burn(&self, key: &str, ttl: Duration) -> Result<bool, RauthyError> {
+        let mut conn = self.connection.clone();
+        let set: Option<String> = redis::cmd("SET")
+            .arg(self.key(&format!("burned:{}", key)))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl.as_secs().max(1))
+            .query_async(&mut conn)
+            .await?;
+        Ok(set.is_some())
+    }
+
+    pub async fn is_locked_out(&self, key: &str) -> Result<bool, RauthyError> {
+        let mut conn = self.connection.clone();
+        let locked: bool = conn
+            .exists(self.key(&format!("lockout:{}", key)))
+            .await?;
+        Ok(locked)
+    }
+
+    async fn publish(&self, invalidation: IpInvalidation) -> Result<(), RauthyError> {
+        let mut conn = self.connection.clone();
+        let _: () = conn
+            .publish(
+                self.key("invalidate"),
+                serde_json::to_string(&invalidation)?,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Forwards invalidations from other replicas until the subscription drops
+    pub async fn subscribe(&self, tx: UnboundedSender<IpInvalidation>) -> Result<(), RauthyError> {
+        let mut pubsub = self.client.get_async_connection().await?.into_pubsub();
+        pubsub.subscribe(self.key("invalidate")).await?;
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let payload: String = message.get_payload()?;
+            match serde_json::from_str::<IpInvalidation>(payload.as_str()) {
+                Ok(invalidation) => {
+                    if tx.send(invalidation).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => log::warn!("Ignoring invalid invalidation message: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
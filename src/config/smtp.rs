@@ -0,0 +1,50 @@
+use crate::config::secret::Secret;
+use crate::error::RauthyError;
+
+/// How the connection to the SMTP server is secured
+#[derive(Clone, Debug, PartialEq)]
+pub enum SmtpSecurity {
+    /// TLS from the start, usually port 465
+    Tls,
+    /// Plain connection upgraded with `STARTTLS`, usually port 587
+    StartTls,
+    /// No encryption, only for local relays and test sinks
+    None,
+}
+
+impl SmtpSecurity {
+    pub fn default_port(&self) -> u16 {
+        match self {
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::StartTls => 587,
+            SmtpSecurity::None => 25,
+        }
+    }
+}
+
+impl std::str::FromStr for SmtpSecurity {
+    type Err = RauthyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tls" => Ok(SmtpSecurity::Tls),
+            "starttls" => Ok(SmtpSecurity::StartTls),
+            "none" => Ok(SmtpSecurity::None),
+            _ => Err(RauthyError::ConfigError(format!(
+                "Invalid SMTP_SECURITY {}, expected tls, starttls or none",
+                s
+            ))),
+        }
+    }
+}
+
+/// The server login links are sent through
+#[derive(Clone, Debug)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    pub from: String,
+}
@@ -0,0 +1,738 @@
+use crate::config::auth_options::{AuthOptions, Username};
+use crate::config::command::UserCommand;
+use crate::config::invite::Invite;
+use crate::config::secret::Secret;
+use crate::config::user::{Login, User};
+use crate::error::RauthyError;
+use chrono::{DateTime, Utc};
+use ipnetwork::IpNetwork;
+use regex::Regex;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, Row, Transaction, NO_PARAMS};
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+// Each entry is applied once, in order, and recorded in `PRAGMA user_version`.
+// Never edit a released migration, append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: Initial schema
+    "CREATE TABLE users (
+        username TEXT PRIMARY KEY NOT NULL
+    );
+    CREATE TABLE credentials (
+        credential TEXT PRIMARY KEY NOT NULL,
+        username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE
+    );
+    CREATE TABLE tokens (
+        token TEXT PRIMARY KEY NOT NULL,
+        username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE
+    );
+    CREATE TABLE ip_grants (
+        ip TEXT NOT NULL,
+        username TEXT REFERENCES users(username) ON DELETE CASCADE,
+        UNIQUE (ip, username)
+    );
+    CREATE INDEX ip_grants_ip ON ip_grants (ip);
+    CREATE TABLE domains (
+        regex TEXT PRIMARY KEY NOT NULL
+    );
+    CREATE TABLE commands (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        name TEXT,
+        path TEXT,
+        command TEXT NOT NULL
+    );",
+    // 2: Per command timeouts
+    "ALTER TABLE commands ADD COLUMN timeout INTEGER;",
+    // 3: Command arguments and shell mode
+    "ALTER TABLE commands ADD COLUMN args TEXT NOT NULL DEFAULT '[]';
+    ALTER TABLE commands ADD COLUMN shell INTEGER NOT NULL DEFAULT 0;",
+    // 4: Lifecycle hooks and IP grant expiry
+    "ALTER TABLE commands ADD COLUMN hook TEXT NOT NULL DEFAULT 'on_login';
+    CREATE TABLE global_commands (
+        position INTEGER PRIMARY KEY NOT NULL,
+        name TEXT,
+        path TEXT,
+        command TEXT NOT NULL,
+        timeout INTEGER,
+        args TEXT NOT NULL DEFAULT '[]',
+        shell INTEGER NOT NULL DEFAULT 0,
+        hook TEXT NOT NULL DEFAULT 'on_login'
+    );
+    CREATE TABLE ip_expiry (
+        ip TEXT PRIMARY KEY NOT NULL,
+        expires_at TEXT NOT NULL
+    );",
+    // 5: Deny list, kind is one of ip, user or token
+    "CREATE TABLE deny_list (
+        kind TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (kind, value)
+    );",
+    // 6: User records, created_at is NULL for users that only exist through their credentials
+    "ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE users ADD COLUMN expires_at TEXT;
+    ALTER TABLE users ADD COLUMN created_at TEXT;
+    ALTER TABLE users ADD COLUMN last_login_at TEXT;
+    ALTER TABLE users ADD COLUMN metadata TEXT NOT NULL DEFAULT '{}';",
+    // 7: Login history, only kept for users with a record
+    "CREATE TABLE logins (
+        username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+        at TEXT NOT NULL,
+        ip TEXT,
+        auth_type TEXT NOT NULL,
+        host TEXT,
+        user_agent TEXT
+    );
+    CREATE INDEX logins_username ON logins (username, at);",
+    // 8: Pending invites, not tied to users as the invitee has no record until they accept
+    "CREATE TABLE invites (
+        token_hash TEXT PRIMARY KEY NOT NULL,
+        username TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        expires_at TEXT NOT NULL,
+        groups TEXT NOT NULL DEFAULT '[]'
+    );",
+    // 9: TOTP secrets enrolled through invites
+    "ALTER TABLE users ADD COLUMN totp_secret TEXT;",
+];
+
+const COMMAND_COLUMNS: &str = "name, path, command, timeout, args, shell, hook";
+
+fn parse_time(value: Option<String>) -> Option<DateTime<Utc>> {
+    value
+        .and_then(|v| DateTime::parse_from_rfc3339(&v).ok())
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Reads the `COMMAND_COLUMNS` starting at column `offset`.
+/// Fails on unreadable `args` or `hook` rather than running the command with different ones.
+fn read_command(r: &Row, offset: usize) -> rusqlite::Result<UserCommand> {
+    let invalid = |column: usize, e: RauthyError| {
+        rusqlite::Error::FromSqlConversionFailure(column, Type::Text, Box::new(e))
+    };
+    Ok(UserCommand {
+        name: r.get(offset)?,
+        path: r.get(offset + 1)?,
+        command: r.get(offset + 2)?,
+        timeout: r.get::<_, Option<i64>>(offset + 3)?.map(|t| t as u64),
+        args: serde_json::from_str(&r.get::<_, String>(offset + 4)?)
+            .map_err(|e| invalid(offset + 4, e.into()))?,
+        shell: r.get(offset + 5)?,
+        hook: r
+            .get::<_, String>(offset + 6)?
+            .parse()
+            .map_err(|e| invalid(offset + 6, e))?,
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct SqliteStore {
+    path: String,
+}
+
+impl SqliteStore {
+    pub fn new(path: String) -> Self {
+        Self { path }
+    }
+
+    fn connect(&self) -> Result<Connection, RauthyError> {
+        let conn = Connection::open(&self.path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")?;
+        Ok(conn)
+    }
+
+    /// Checks the database can be opened and queried
+    pub fn ping(&self) -> Result<(), RauthyError> {
+        let conn = self.connect()?;
+        conn.query_row("PRAGMA user_version", NO_PARAMS, |r| r.get::<_, i64>(0))?;
+        Ok(())
+    }
+
+    /// Applies any outstanding migrations and returns the resulting schema version
+    pub fn migrate(&self) -> Result<usize, RauthyError> {
+        let mut conn = self.connect()?;
+        Self::apply_migrations(&mut conn)
+    }
+
+    /// Whether the database holds no users, grants, domains, commands, deny entries or invites
+    pub fn is_empty(&self) -> Result<bool, RauthyError> {
+        let mut conn = self.connect()?;
+        Self::apply_migrations(&mut conn)?;
+        let rows: i64 = conn.query_row(
+            "SELECT (SELECT COUNT(*) FROM users)
+                + (SELECT COUNT(*) FROM ip_grants)
+                + (SELECT COUNT(*) FROM domains)
+                + (SELECT COUNT(*) FROM global_commands)
+                + (SELECT COUNT(*) FROM deny_list)
+                + (SELECT COUNT(*) FROM invites)",
+            NO_PARAMS,
+            |r| r.get(0),
+        )?;
+        Ok(rows == 0)
+    }
+
+    fn apply_migrations(conn: &mut Connection) -> Result<usize, RauthyError> {
+        let version: i64 = conn.query_row("PRAGMA user_version", NO_PARAMS, |r| r.get(0))?;
+        let version = version as usize;
+        if version > MIGRATIONS.len() {
+            return Err(RauthyError::StoreError(format!(
+                "Database schema version {} is newer than this build supports ({})",
+                version,
+                MIGRATIONS.len()
+            )));
+        }
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            log::info!("Applying database migration {}", index + 1);
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.execute_batch(&format!("PRAGMA user_version = {}", index + 1))?;
+            tx.commit()?;
+        }
+        Ok(MIGRATIONS.len())
+    }
+
+    pub fn load(&self) -> Result<AuthOptions, RauthyError> {
+        let mut conn = self.connect()?;
+        Self::apply_migrations(&mut conn)?;
+        let mut auth_options = AuthOptions::default();
+
+        let mut stmt = conn.prepare(
+            "SELECT username, disabled, expires_at, created_at, last_login_at, metadata, totp_secret
+            FROM users WHERE created_at IS NOT NULL",
+        )?;
+        let rows = stmt.query_map(NO_PARAMS, |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, bool>(1)?,
+                r.get::<_, Option<String>>(2)?,
+                r.get::<_, Option<String>>(3)?,
+                r.get::<_, Option<String>>(4)?,
+                r.get::<_, String>(5)?,
+                r.get::<_, Option<String>>(6)?,
+            ))
+        })?;
+        for row in rows {
+            let (username, disabled, expires_at, created_at, last_login_at, metadata, totp_secret) =
+                row?;
+            let created_at = match parse_time(created_at) {
+                Some(created_at) => created_at,
+                None => {
+                    log::warn!("Skipping user {} with an invalid created_at", username);
+                    continue;
+                }
+            };
+            let user = User {
+                disabled,
+                expires_at: parse_time(expires_at),
+                created_at,
+                last_login_at: parse_time(last_login_at),
+                metadata: serde_json::from_str(&metadata).unwrap_or_default(),
+                logins: vec![],
+                totp_secret: totp_secret.map(Secret::from),
+            };
+            auth_options.users.insert(username.into(), user);
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT username, at, ip, auth_type, host, user_agent FROM logins ORDER BY username, at",
+        )?;
+        let rows = stmt.query_map(NO_PARAMS, |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, Option<String>>(2)?,
+                r.get::<_, String>(3)?,
+                r.get::<_, Option<String>>(4)?,
+                r.get::<_, Option<String>>(5)?,
+            ))
+        })?;
+        for row in rows {
+            let (username, at, ip, auth_type, host, user_agent) = row?;
+            let at = match parse_time(Some(at)) {
+                Some(at) => at,
+                None => {
+                    log::warn!("Skipping login of {} with an invalid time", username);
+                    continue;
+                }
+            };
+            let login = Login {
+                at,
+                ip: ip.and_then(|ip| ip.parse().ok()),
+                auth_type,
+                host,
+                user_agent,
+            };
+            if let Some(user) = auth_options.users.get_mut(&Username::from(username)) {
+                user.logins.push(login);
+            }
+        }
+
+        let mut stmt = conn.prepare("SELECT credential, username FROM credentials")?;
+        let rows = stmt.query_map(NO_PARAMS, |r| {
+            Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (credential, username) = row?;
+            auth_options.passwords.insert(credential, username.into());
+        }
+
+        let mut stmt = conn.prepare("SELECT token, username FROM tokens")?;
+        let rows = stmt.query_map(NO_PARAMS, |r| {
+            Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (token, username) = row?;
+            auth_options.tokens.insert(token, username.into());
+        }
+
+        let mut stmt = conn.prepare("SELECT ip, username FROM ip_grants")?;
+        let rows = stmt.query_map(NO_PARAMS, |r| {
+            Ok((r.get::<_, String>(0)?, r.get::<_, Option<String>>(1)?))
+        })?;
+        for row in rows {
+            let (ip, username) = row?;
+            let ip = ip
+                .parse::<IpAddr>()
+                .map_err(|e| RauthyError::StoreError(format!("Invalid IP {}: {}", ip, e)))?;
+            let username: Option<Username> = username.map(|u| u.into());
+            auth_options.add_ip_and_user(ip, username.as_ref());
+        }
+
+        let mut stmt = conn.prepare("SELECT regex FROM domains")?;
+        let rows = stmt.query_map(NO_PARAMS, |r| r.get::<_, String>(0))?;
+        for row in rows {
+            auth_options.domains.push(Regex::new(&row?)?);
+        }
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT username, {} FROM commands ORDER BY username, position",
+            COMMAND_COLUMNS
+        ))?;
+        let rows = stmt.query_map(NO_PARAMS, |r| {
+            Ok((r.get::<_, String>(0)?, read_command(r, 1)?))
+        })?;
+        for row in rows {
+            let (username, command) = row?;
+            auth_options
+                .commands
+                .entry(username.into())
+                .or_insert(vec![])
+                .push(command);
+        }
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM global_commands ORDER BY position",
+            COMMAND_COLUMNS
+        ))?;
+        let rows = stmt.query_map(NO_PARAMS, |r| read_command(r, 0))?;
+        for row in rows {
+            auth_options.global_commands.push(row?);
+        }
+
+        let mut stmt = conn.prepare("SELECT ip, expires_at FROM ip_expiry")?;
+        let rows = stmt.query_map(NO_PARAMS, |r| {
+            Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (ip, expires_at) = row?;
+            match (
+                ip.parse::<IpAddr>(),
+                DateTime::parse_from_rfc3339(&expires_at),
+            ) {
+                (Ok(ip), Ok(expires_at)) => {
+                    auth_options.set_ip_expiry(ip, expires_at.with_timezone(&Utc));
+                }
+                _ => log::warn!("Skipping invalid expiry {} for IP {}", expires_at, ip),
+            }
+        }
+
+        let mut stmt = conn.prepare("SELECT kind, value FROM deny_list ORDER BY rowid")?;
+        let rows = stmt.query_map(NO_PARAMS, |r| {
+            Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (kind, value) = row?;
+            let deny = &mut auth_options.deny;
+            match kind.as_str() {
+                "ip" => match value.parse::<IpNetwork>() {
+                    Ok(network) => deny.add_ip(network),
+                    Err(e) => log::warn!("Skipping invalid denied IP {}: {}", value, e),
+                },
+                // Written by older versions, denied users are disabled users now
+                "user" => auth_options.user_mut(&value.into()).disabled = true,
+                "token" => deny.add_token(value.into()),
+                _ => log::warn!("Skipping unknown deny list entry {} {}", kind, value),
+            }
+        }
+
+        let mut stmt = conn
+            .prepare("SELECT token_hash, username, created_at, expires_at, groups FROM invites")?;
+        let rows = stmt.query_map(NO_PARAMS, |r| {
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, String>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, String>(3)?,
+                r.get::<_, String>(4)?,
+            ))
+        })?;
+        for row in rows {
+            let (token_hash, username, created_at, expires_at, groups) = row?;
+            match (parse_time(Some(created_at)), parse_time(Some(expires_at))) {
+                (Some(created_at), Some(expires_at)) => {
+                    let invite = Invite {
+                        username: username.into(),
+                        created_at,
+                        expires_at,
+                        groups: serde_json::from_str(&groups).unwrap_or_default(),
+                    };
+                    auth_options.invites.insert(token_hash, invite);
+                }
+                _ => log::warn!("Skipping invite for {} with an invalid time", username),
+            }
+        }
+
+        Ok(auth_options)
+    }
+
+    /// Replaces the stored contents with `auth_options` in a single transaction
+    pub fn save(&self, auth_options: &AuthOptions) -> Result<(), RauthyError> {
+        let mut conn = self.connect()?;
+        Self::apply_migrations(&mut conn)?;
+        let tx = conn.transaction()?;
+        tx.execute_batch(
+            "DELETE FROM invites;
+            DELETE FROM logins;
+            DELETE FROM deny_list;
+            DELETE FROM ip_expiry;
+            DELETE FROM global_commands;
+            DELETE FROM commands;
+            DELETE FROM domains;
+            DELETE FROM ip_grants;
+            DELETE FROM tokens;
+            DELETE FROM credentials;
+            DELETE FROM users;",
+        )?;
+        Self::insert_users(&tx, auth_options)?;
+
+        {
+            let mut stmt =
+                tx.prepare("INSERT INTO credentials (credential, username) VALUES (?1, ?2)")?;
+            for (credential, username) in auth_options.passwords.iter() {
+                stmt.execute(params![credential, username.to_string()])?;
+            }
+
+            let mut stmt = tx.prepare("INSERT INTO tokens (token, username) VALUES (?1, ?2)")?;
+            for (token, username) in auth_options.tokens.iter() {
+                stmt.execute(params![token, username.to_string()])?;
+            }
+
+            for (ip, usernames) in auth_options.ips.iter() {
+                Self::insert_grants(&tx, ip, usernames)?;
+            }
+
+            let mut stmt = tx.prepare("INSERT OR IGNORE INTO domains (regex) VALUES (?1)")?;
+            for domain in auth_options.domains.iter() {
+                stmt.execute(params![domain.as_str()])?;
+            }
+
+            let mut stmt = tx.prepare(&format!(
+                "INSERT INTO commands (username, position, {})
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                COMMAND_COLUMNS
+            ))?;
+            for (username, commands) in auth_options.commands.iter() {
+                for (position, command) in commands.iter().enumerate() {
+                    stmt.execute(params![
+                        username.to_string(),
+                        position as i64,
+                        command.name,
+                        command.path,
+                        command.command,
+                        command.timeout.map(|t| t as i64),
+                        serde_json::to_string(&command.args)?,
+                        command.shell,
+                        command.hook.to_string()
+                    ])?;
+                }
+            }
+
+            let mut stmt = tx.prepare(&format!(
+                "INSERT INTO global_commands (position, {})
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                COMMAND_COLUMNS
+            ))?;
+            for (position, command) in auth_options.global_commands.iter().enumerate() {
+                stmt.execute(params![
+                    position as i64,
+                    command.name,
+                    command.path,
+                    command.command,
+                    command.timeout.map(|t| t as i64),
+                    serde_json::to_string(&command.args)?,
+                    command.shell,
+                    command.hook.to_string()
+                ])?;
+            }
+
+            let mut stmt = tx.prepare("INSERT INTO ip_expiry (ip, expires_at) VALUES (?1, ?2)")?;
+            for (ip, expires_at) in auth_options.ip_expiry.iter() {
+                stmt.execute(params![ip.to_string(), expires_at.to_rfc3339()])?;
+            }
+
+            let mut stmt = tx.prepare("INSERT INTO deny_list (kind, value) VALUES (?1, ?2)")?;
+            for (kind, value) in auth_options.deny.entries() {
+                stmt.execute(params![kind, value])?;
+            }
+
+            for (username, user) in auth_options.users.iter() {
+                Self::insert_logins(&tx, username, user)?;
+            }
+
+            let mut stmt = tx.prepare(
+                "INSERT INTO invites (token_hash, username, created_at, expires_at, groups)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for (token_hash, invite) in auth_options.invites.iter() {
+                stmt.execute(params![
+                    token_hash,
+                    invite.username.to_string(),
+                    invite.created_at.to_rfc3339(),
+                    invite.expires_at.to_rfc3339(),
+                    serde_json::to_string(&invite.groups)?
+                ])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Writes only the rows of `ips` and `users` instead of replacing everything,
+    /// used to persist queued IP grants and logins
+    pub fn save_changes(
+        &self,
+        auth_options: &AuthOptions,
+        ips: &[IpAddr],
+        users: &[Username],
+    ) -> Result<(), RauthyError> {
+        let mut conn = self.connect()?;
+        Self::apply_migrations(&mut conn)?;
+        let tx = conn.transaction()?;
+
+        // Grants reference their users, so every user of a changed IP needs a row first
+        let mut usernames: HashSet<&Username> = users.iter().collect();
+        for ip in ips {
+            usernames.extend(auth_options.ips.get(ip).into_iter().flatten());
+        }
+        for username in usernames {
+            Self::upsert_user(&tx, auth_options, username)?;
+        }
+        for username in users {
+            tx.execute(
+                "DELETE FROM logins WHERE username = ?1",
+                params![username.to_string()],
+            )?;
+            if let Some(user) = auth_options.users.get(username) {
+                Self::insert_logins(&tx, username, user)?;
+            }
+        }
+        for ip in ips {
+            tx.execute(
+                "DELETE FROM ip_grants WHERE ip = ?1",
+                params![ip.to_string()],
+            )?;
+            tx.execute(
+                "DELETE FROM ip_expiry WHERE ip = ?1",
+                params![ip.to_string()],
+            )?;
+            if let Some(usernames) = auth_options.ips.get(ip) {
+                Self::insert_grants(&tx, ip, usernames)?;
+            }
+            if let Some(expires_at) = auth_options.ip_expiry.get(ip) {
+                tx.execute(
+                    "INSERT INTO ip_expiry (ip, expires_at) VALUES (?1, ?2)",
+                    params![ip.to_string(), expires_at.to_rfc3339()],
+                )?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn insert_users(tx: &Transaction, auth_options: &AuthOptions) -> Result<(), RauthyError> {
+        let mut usernames: HashSet<&Username> = HashSet::new();
+        usernames.extend(auth_options.passwords.values());
+        usernames.extend(auth_options.tokens.values());
+        usernames.extend(auth_options.ips.values().flatten());
+        usernames.extend(auth_options.commands.keys());
+        usernames.extend(auth_options.users.keys());
+        for username in usernames {
+            Self::upsert_user(tx, auth_options, username)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the user's record, users without one only get a row if they have none yet
+    fn upsert_user(
+        tx: &Transaction,
+        auth_options: &AuthOptions,
+        username: &Username,
+    ) -> Result<(), RauthyError> {
+        match auth_options.users.get(username) {
+            Some(user) => tx.execute(
+                "INSERT INTO users (username, disabled, expires_at, created_at, last_login_at, metadata, totp_secret)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ON CONFLICT (username) DO UPDATE SET
+                    disabled = excluded.disabled,
+                    expires_at = excluded.expires_at,
+                    created_at = excluded.created_at,
+                    last_login_at = excluded.last_login_at,
+                    metadata = excluded.metadata,
+                    totp_secret = excluded.totp_secret",
+                params![
+                    username.to_string(),
+                    user.disabled,
+                    user.expires_at.map(|t| t.to_rfc3339()),
+                    user.created_at.to_rfc3339(),
+                    user.last_login_at.map(|t| t.to_rfc3339()),
+                    serde_json::to_string(&user.metadata)?,
+                    user.totp_secret.as_ref().map(Secret::expose)
+                ],
+            )?,
+            None => tx.execute(
+                "INSERT OR IGNORE INTO users (username) VALUES (?1)",
+                params![username.to_string()],
+            )?,
+        };
+        Ok(())
+    }
+
+    fn insert_grants(
+        tx: &Transaction,
+        ip: &IpAddr,
+        usernames: &[Username],
+    ) -> Result<(), RauthyError> {
+        let mut stmt =
+            tx.prepare_cached("INSERT OR IGNORE INTO ip_grants (ip, username) VALUES (?1, ?2)")?;
+        if usernames.is_empty() {
+            stmt.execute(params![ip.to_string(), Option::<String>::None])?;
+        }
+        for username in usernames {
+            stmt.execute(params![ip.to_string(), username.to_string()])?;
+        }
+        Ok(())
+    }
+
+    fn insert_logins(tx: &Transaction, username: &Username, user: &User) -> Result<(), RauthyError> {
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO logins (username, at, ip, auth_type, host, user_agent)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+        for login in user.logins.iter() {
+            stmt.execute(params![
+                username.to_string(),
+                login.at.to_rfc3339(),
+                login.ip.map(|ip| ip.to_string()),
+                login.auth_type,
+                login.host,
+                login.user_agent
+            ])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_store() -> (PathBuf, SqliteStore) {
+        let dir = std::env::temp_dir().join(format!("rauthy-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rauthy.db");
+        let store = SqliteStore::new(path.to_string_lossy().to_string());
+        (dir, store)
+    }
+
+    fn user_version(store: &SqliteStore) -> i64 {
+        let conn = store.connect().unwrap();
+        conn.query_row("PRAGMA user_version", NO_PARAMS, |r| r.get(0))
+            .unwrap()
+    }
+
+    const AUTH_JSON: &str = r#"{
+        "ips": {"10.0.0.1": ["alice"], "10.0.0.2": []},
+        "passwords": {"YWxpY2U6c2VjcmV0": "alice"},
+        "commands": {"alice": [{"name": "greet", "path": "/tmp", "command": "echo", "args": ["hi"], "shell": false, "timeout": 5, "hook": "on_login"}]},
+        "tokens": {"bypass": "bob"},
+        "domains": ["^example\\.com$"],
+        "global_commands": [{"name": null, "path": null, "command": "true", "timeout": null, "hook": "on_logout"}],
+        "ip_expiry": {"10.0.0.1": "2030-01-01T00:00:00Z"},
+        "deny": {"ips": ["192.168.0.0/24"], "tokens": ["stolen"]},
+        "users": {"alice": {
+            "disabled": false,
+            "expires_at": "2031-01-01T00:00:00Z",
+            "created_at": "2020-01-01T00:00:00Z",
+            "last_login_at": "2020-02-01T00:00:00Z",
+            "metadata": {"team": "ops"},
+            "totp_secret": "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ",
+            "logins": [{"at": "2020-02-01T00:00:00Z", "ip": "10.0.0.1", "auth_type": "password", "host": "example.com", "user_agent": null}]
+        }},
+        "invites": {"abc123": {"username": "carol", "created_at": "2020-01-01T00:00:00Z", "expires_at": "2030-01-01T00:00:00Z", "groups": ["dev"]}}
+    }"#;
+
+    #[test]
+    fn migrate_applies_every_migration_once() {
+        let (dir, store) = temp_store();
+        assert_eq!(store.migrate().unwrap(), MIGRATIONS.len());
+        assert_eq!(user_version(&store), MIGRATIONS.len() as i64);
+        assert_eq!(store.migrate().unwrap(), MIGRATIONS.len());
+        assert!(store.is_empty().unwrap());
+
+        let conn = store.connect().unwrap();
+        conn.execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len() + 1))
+            .unwrap();
+        let error = store.migrate().unwrap_err().to_string();
+        assert!(error.contains("newer"), "{}", error);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn save_and_load_round_trip_an_auth_file() {
+        let (dir, store) = temp_store();
+        let auth_options = AuthOptions::from_string(AUTH_JSON.to_string()).unwrap();
+        store.save(&auth_options).unwrap();
+        assert!(!store.is_empty().unwrap());
+
+        let loaded = store.load().unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&auth_options).unwrap()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn save_changes_only_writes_the_given_ips() {
+        let (dir, store) = temp_store();
+        let mut auth_options = AuthOptions::from_string(AUTH_JSON.to_string()).unwrap();
+        store.save(&auth_options).unwrap();
+
+        let ip: IpAddr = "10.0.0.3".parse().unwrap();
+        auth_options.add_ip_and_user(ip, Some(&"bob".into()));
+        auth_options.remove_ip(&"10.0.0.2".parse().unwrap());
+        store.save_changes(&auth_options, &[ip], &[]).unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.ips.get(&ip), Some(&vec!["bob".into()]));
+        assert!(loaded.ips.contains_key(&"10.0.0.2".parse().unwrap()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
+
+/// Codes as authenticator apps show them by default, RFC 6238 with HMAC-SHA1
+pub const CODE_LENGTH: usize = 6;
+const STEP_SECS: i64 = 30;
+/// Codes of the steps just before and after the current one are accepted too, for clock drift and slow typing
+const SKEW_STEPS: i64 = 1;
+
+const ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+/// The secret as authenticator apps take it, base32 without padding
+pub fn encode_secret(key: &[u8]) -> String {
+    base32::encode(ALPHABET, key)
+}
+
+fn code(key: &[u8], counter: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let mut truncated = [0; 4];
+    truncated.copy_from_slice(&hash[offset..offset + 4]);
+    (u32::from_be_bytes(truncated) & 0x7fff_ffff) % 10u32.pow(CODE_LENGTH as u32)
+}
+
+/// Whether `code` is valid for `secret` at `now`
+pub fn verify(secret: &str, code: &str, now: DateTime<Utc>) -> bool {
+    let key = match base32::decode(ALPHABET, secret) {
+        Some(key) => key,
+        None => return false,
+    };
+    if code.len() != CODE_LENGTH || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    let code: u32 = match code.parse() {
+        Ok(code) => code,
+        Err(_) => return false,
+    };
+    let counter = now.timestamp() / STEP_SECS;
+    (-SKEW_STEPS..=SKEW_STEPS).any(|skew| self::code(&key, counter + skew) == code)
+}
+
+/// Splits the code a TOTP user appends to their password off it, `None` if it is too short to carry one
+pub fn split_code(password: &str) -> Option<(&str, &str)> {
+    let at = password
+        .len()
+        .checked_sub(CODE_LENGTH)
+        .filter(|at| *at > 0)?;
+    if !password.is_char_boundary(at) {
+        return None;
+    }
+    Some(password.split_at(at))
+}
+
+/// The `otpauth://` URI authenticator apps enroll from, usually scanned from a QR code
+pub fn uri(secret: &str, username: &str) -> String {
+    format!(
+        "otpauth://totp/Rauthy:{}?secret={}&issuer=Rauthy&algorithm=SHA1&digits={}&period={}",
+        percent_encode(username),
+        secret,
+        CODE_LENGTH,
+        STEP_SECS
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn verify_matches_the_rfc_6238_test_vectors() {
+        let secret = encode_secret(b"12345678901234567890");
+        let at = |secs| Utc.timestamp_opt(secs, 0).unwrap();
+        assert!(verify(&secret, "287082", at(59)));
+        assert!(verify(&secret, "081804", at(1111111109)));
+        assert!(verify(&secret, "005924", at(1234567890)));
+        // One step either side is still accepted, two are not
+        assert!(verify(&secret, "081804", at(1111111109 + 30)));
+        assert!(!verify(&secret, "081804", at(1111111109 + 60)));
+        assert!(!verify(&secret, "81804", at(1111111109)));
+        assert!(!verify(&secret, "+81804", at(1111111109)));
+        assert!(!verify("not base32!", "081804", at(1111111109)));
+    }
+
+    #[test]
+    fn split_code_takes_the_last_digits() {
+        assert_eq!(split_code("hunter22123456"), Some(("hunter22", "123456")));
+        assert_eq!(split_code("123456"), None);
+        assert_eq!(split_code("€12345"), None);
+    }
+
+    #[test]
+    fn uri_encodes_the_username() {
+        assert_eq!(
+            uri("ABC", "a b"),
+            "otpauth://totp/Rauthy:a%20b?secret=ABC&issuer=Rauthy&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
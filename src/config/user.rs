@@ -0,0 +1,137 @@
+use crate::config::auth_options::Username;
+use crate::config::secret::Secret;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Logins from the same IP with the same auth type this close to the previous one update it instead of adding one,
+/// credentials sent with every request would otherwise fill the history
+const LOGIN_MERGE_WINDOW_SECS: i64 = 3600;
+
+/// Account details for a user, users that only appear in passwords, tokens or IPs have no record and are always active
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct User {
+    #[serde(default)]
+    pub disabled: bool,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub last_login_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// Most recent last, bounded by `LOGIN_HISTORY_SIZE`
+    #[serde(default)]
+    pub logins: Vec<Login>,
+    /// Base32 TOTP secret set when the user enrolled through an invite, their passwords must be followed by a code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<Secret>,
+}
+
+/// A successful login
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Login {
+    pub at: DateTime<Utc>,
+    pub ip: Option<IpAddr>,
+    pub auth_type: String,
+    pub host: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Everything known about a user, shown by `rauthy user show` and `GET /_rauthy/admin/api/users/{name}`
+#[derive(Serialize, Debug)]
+pub struct UserDetails {
+    pub username: Username,
+    /// `active`, `denied`, `disabled` or `expired`
+    pub status: &'static str,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_login_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub metadata: HashMap<String, String>,
+    pub passwords: usize,
+    pub tokens: usize,
+    pub ips: Vec<IpAddr>,
+    pub commands: usize,
+    /// Whether the user's passwords must be followed by a TOTP code
+    pub totp: bool,
+    /// Bypass token authentications, only known with `REDIS_URL` set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_uses: Option<u64>,
+    pub logins: Vec<Login>,
+}
+
+impl User {
+    pub fn new(created_at: DateTime<Utc>) -> Self {
+        Self {
+            disabled: false,
+            expires_at: None,
+            created_at,
+            last_login_at: None,
+            metadata: HashMap::new(),
+            logins: vec![],
+            totp_secret: None,
+        }
+    }
+
+    /// Records `login`, keeping only the most recent `keep` entries of the history.
+    /// A repeat of the latest login within `LOGIN_MERGE_WINDOW_SECS` replaces it.
+    pub fn record_login(&mut self, login: Login, keep: usize) {
+        self.last_login_at = Some(login.at);
+        if let Some(last) = self.logins.last_mut() {
+            let repeat = last.ip == login.ip
+                && last.auth_type == login.auth_type
+                && (login.at - last.at).num_seconds() < LOGIN_MERGE_WINDOW_SECS;
+            if repeat {
+                *last = login;
+                return;
+            }
+        }
+        self.logins.push(login);
+        if self.logins.len() > keep {
+            self.logins.drain(..self.logins.len() - keep);
+        }
+    }
+
+    /// Why the user may not log in at `now`, `None` if they can
+    pub fn inactive_reason(&self, now: DateTime<Utc>) -> Option<&'static str> {
+        if self.disabled {
+            Some("disabled")
+        } else if self.expires_at.map(|e| e <= now).unwrap_or(false) {
+            Some("expired")
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn login(at: DateTime<Utc>, ip: &str, auth_type: &str) -> Login {
+        Login {
+            at,
+            ip: Some(ip.parse().unwrap()),
+            auth_type: auth_type.to_string(),
+            host: None,
+            user_agent: None,
+        }
+    }
+
+    #[test]
+    fn record_login_merges_repeats_within_the_window() {
+        let now = Utc::now();
+        let minutes = |m| now + Duration::minutes(m);
+        let mut user = User::new(now);
+        user.record_login(login(now, "10.0.0.1", "BasicAuth"), 20);
+        user.record_login(login(minutes(5), "10.0.0.1", "BasicAuth"), 20);
+        assert_eq!(user.logins.len(), 1);
+        assert_eq!(user.logins[0].at, minutes(5));
+
+        user.record_login(login(minutes(6), "10.0.0.2", "BasicAuth"), 20);
+        user.record_login(login(minutes(7), "10.0.0.2", "BypassTokenQuery"), 20);
+        user.record_login(login(minutes(180), "10.0.0.2", "BypassTokenQuery"), 20);
+        assert_eq!(user.logins.len(), 4);
+        assert_eq!(user.last_login_at, Some(minutes(180)));
+    }
+}
@@ -2,16 +2,19 @@ use crate::error::RauthyError::ConfigError;
 use serde::export::Formatter;
 use std::error::Error;
 use std::fmt::{Display, Result};
+use std::time::Duration;
 use warp::Rejection;
 
 #[derive(Debug)]
 pub enum RauthyError {
     Generic,
     CommandError(String),
+    CommandTimeout(Duration),
     ServerError(String),
     ConfigError(String),
     UserCommandError(String),
     RegexError(String),
+    StoreError(String),
 }
 
 impl Error for RauthyError {}
@@ -20,11 +23,15 @@ impl Display for RauthyError {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         match self {
             RauthyError::CommandError(s) => write!(f, "Command Execution Error: {}", s),
+            RauthyError::CommandTimeout(d) => {
+                write!(f, "Command Execution Error: Timed out after {:?}", d)
+            }
             RauthyError::ServerError(s) => write!(f, "Server Error: {}", s),
             RauthyError::ConfigError(s) => write!(f, "Config Error: {}", s),
             RauthyError::Generic => write!(f, "General Error"),
             RauthyError::UserCommandError(s) => write!(f, "User Command Error: {}", s),
             RauthyError::RegexError(s) => write!(f, "Regex Error: {}", s),
+            RauthyError::StoreError(s) => write!(f, "Store Error: {}", s),
         }
     }
 }
@@ -46,6 +53,31 @@ impl From<regex::Error> for RauthyError {
         Self::RegexError(e.to_string())
     }
 }
+
+impl From<rusqlite::Error> for RauthyError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::StoreError(e.to_string())
+    }
+}
+
+impl From<redis::RedisError> for RauthyError {
+    fn from(e: redis::RedisError) -> Self {
+        Self::StoreError(e.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for RauthyError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Self::StoreError(e.to_string())
+    }
+}
+
+impl From<prometheus::Error> for RauthyError {
+    fn from(e: prometheus::Error) -> Self {
+        Self::ServerError(e.to_string())
+    }
+}
+
 impl warp::reject::Reject for RauthyError {}
 
 impl From<RauthyError> for Rejection {
@@ -0,0 +1,320 @@
+use crate::config::auth_options::{AuthOptions, Username};
+use crate::config::secret::Secret;
+use crate::config::user::UserDetails;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+
+/// Rows printed by the `list` subcommands, empty cells are shown as `-` and serialized as `null`
+pub struct Listing {
+    columns: &'static [&'static str],
+    rows: Vec<Vec<String>>,
+}
+
+impl Listing {
+    pub fn print(&self, json: bool) {
+        if json {
+            println!("{}", self.to_json());
+        } else {
+            print!("{}", self.to_table());
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let rows: Vec<Value> = self
+            .rows
+            .iter()
+            .map(|row| {
+                let mut object = Map::new();
+                for (column, cell) in self.columns.iter().zip(row.iter()) {
+                    let value = if cell.is_empty() {
+                        Value::Null
+                    } else {
+                        Value::String(cell.clone())
+                    };
+                    object.insert(column.to_string(), value);
+                }
+                Value::Object(object)
+            })
+            .collect();
+        serde_json::to_string_pretty(&rows).unwrap_or_default()
+    }
+
+    fn to_table(&self) -> String {
+        let cell = |c: &String| {
+            if c.is_empty() {
+                "-".to_string()
+            } else {
+                c.clone()
+            }
+        };
+        let mut widths: Vec<usize> = self.columns.iter().map(|c| c.len()).collect();
+        for row in self.rows.iter() {
+            for (width, c) in widths.iter_mut().zip(row.iter()) {
+                *width = (*width).max(cell(c).chars().count());
+            }
+        }
+        let line = |cells: Vec<String>| {
+            let padded: Vec<String> = cells
+                .iter()
+                .zip(widths.iter())
+                .map(|(c, width)| format!("{:<width$}", c, width = width))
+                .collect();
+            format!("{}\n", padded.join("  ").trim_end())
+        };
+        let mut table = line(self.columns.iter().map(|c| c.to_uppercase()).collect());
+        for row in self.rows.iter() {
+            table.push_str(&line(row.iter().map(cell).collect()));
+        }
+        table
+    }
+}
+
+fn timestamp(time: Option<&DateTime<Utc>>) -> String {
+    time.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_default()
+}
+
+fn join(users: &[Username]) -> String {
+    let users: Vec<String> = users.iter().map(|u| u.to_string()).collect();
+    users.join(",")
+}
+
+pub fn users(auth_options: &AuthOptions) -> Listing {
+    let now = Utc::now();
+    let mut usernames: Vec<&Username> = auth_options
+        .passwords
+        .values()
+        .chain(auth_options.tokens.values())
+        .chain(auth_options.ips.values().flatten())
+        .chain(auth_options.commands.keys())
+        .chain(auth_options.users.keys())
+        .collect();
+    usernames.sort_by_key(|u| u.to_string());
+    usernames.dedup();
+
+    let rows = usernames
+        .into_iter()
+        .map(|username| {
+            let count = |n: usize| n.to_string();
+            let user = auth_options.users.get(username);
+            let status = auth_options.user_status(username, now);
+            let mut metadata: Vec<String> = user
+                .map(|u| {
+                    u.metadata
+                        .iter()
+                        .map(|(k, v)| format!("{}={}", k, v))
+                        .collect()
+                })
+                .unwrap_or_default();
+            metadata.sort();
+            vec![
+                username.to_string(),
+                status.to_string(),
+                count(
+                    auth_options
+                        .passwords
+                        .values()
+                        .filter(|u| u == &username)
+                        .count(),
+                ),
+                count(
+                    auth_options
+                        .tokens
+                        .values()
+                        .filter(|u| u == &username)
+                        .count(),
+                ),
+                count(
+                    auth_options
+                        .ips
+                        .values()
+                        .filter(|users| users.contains(username))
+                        .count(),
+                ),
+                count(
+                    auth_options
+                        .commands
+                        .get(username)
+                        .map(|c| c.len())
+                        .unwrap_or(0),
+                ),
+                timestamp(user.map(|u| &u.created_at)),
+                timestamp(user.and_then(|u| u.last_login_at.as_ref())),
+                timestamp(user.and_then(|u| u.expires_at.as_ref())),
+                metadata.join(","),
+            ]
+        })
+        .collect();
+    Listing {
+        columns: &[
+            "username",
+            "status",
+            "passwords",
+            "tokens",
+            "ips",
+            "commands",
+            "created_at",
+            "last_login_at",
+            "expires_at",
+            "metadata",
+        ],
+        rows,
+    }
+}
+
+/// Prints a user's details followed by their login history, most recent first
+pub fn user(details: &UserDetails, json: bool) {
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(details).unwrap_or_default()
+        );
+        return;
+    }
+    let mut metadata: Vec<String> = details
+        .metadata
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    metadata.sort();
+    let ips: Vec<String> = details.ips.iter().map(|ip| ip.to_string()).collect();
+    let token_uses = details
+        .token_uses
+        .map(|u| u.to_string())
+        .unwrap_or_default();
+    let fields = vec![
+        ("username", details.username.to_string()),
+        ("status", details.status.to_string()),
+        ("created_at", timestamp(details.created_at.as_ref())),
+        ("last_login_at", timestamp(details.last_login_at.as_ref())),
+        ("expires_at", timestamp(details.expires_at.as_ref())),
+        ("passwords", details.passwords.to_string()),
+        ("tokens", details.tokens.to_string()),
+        ("totp", details.totp.to_string()),
+        ("commands", details.commands.to_string()),
+        ("token_uses", token_uses),
+        ("ips", ips.join(",")),
+        ("metadata", metadata.join(",")),
+    ];
+    for (name, value) in fields {
+        let value = if value.is_empty() {
+            "-".to_string()
+        } else {
+            value
+        };
+        println!("{:<14} {}", format!("{}:", name), value);
+    }
+
+    let rows = details
+        .logins
+        .iter()
+        .rev()
+        .map(|login| {
+            vec![
+                timestamp(Some(&login.at)),
+                login.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                login.auth_type.clone(),
+                login.host.clone().unwrap_or_default(),
+                login.user_agent.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+    println!();
+    Listing {
+        columns: &["at", "ip", "auth_type", "host", "user_agent"],
+        rows,
+    }
+    .print(false);
+}
+
+pub fn ips(auth_options: &AuthOptions, user: Option<&Username>) -> Listing {
+    let mut ips: Vec<_> = auth_options
+        .ips
+        .iter()
+        .filter(|(_, users)| user.map(|u| users.contains(u)).unwrap_or(true))
+        .collect();
+    ips.sort_by_key(|(ip, _)| **ip);
+    let rows = ips
+        .into_iter()
+        .map(|(ip, users)| {
+            vec![
+                ip.to_string(),
+                join(users),
+                timestamp(auth_options.ip_expiry.get(ip)),
+            ]
+        })
+        .collect();
+    Listing {
+        columns: &["ip", "users", "expires_at"],
+        rows,
+    }
+}
+
+pub fn tokens(auth_options: &AuthOptions) -> Listing {
+    let mut rows: Vec<Vec<String>> = auth_options
+        .tokens
+        .iter()
+        .map(|(token, user)| {
+            let revoked = if auth_options.deny.denies_token(token) {
+                "yes"
+            } else {
+                "no"
+            };
+            // The fingerprint tells tokens apart without giving away any of their characters
+            let token = Secret::from(token.as_str()).to_string();
+            vec![token, user.to_string(), revoked.to_string()]
+        })
+        .collect();
+    rows.sort_by(|a, b| a[1].cmp(&b[1]).then(a[0].cmp(&b[0])));
+    Listing {
+        columns: &["token", "user", "revoked"],
+        rows,
+    }
+}
+
+pub fn domains(auth_options: &AuthOptions) -> Listing {
+    let rows = auth_options
+        .domains
+        .iter()
+        .map(|r| vec![r.as_str().to_string()])
+        .collect();
+    Listing {
+        columns: &["regex"],
+        rows,
+    }
+}
+
+pub fn commands(auth_options: &AuthOptions, user: Option<&Username>) -> Listing {
+    let mut users: Vec<&Username> = auth_options
+        .commands
+        .keys()
+        .filter(|u| user.map(|user| user == *u).unwrap_or(true))
+        .collect();
+    users.sort_by_key(|u| u.to_string());
+    let global = auth_options
+        .global_commands
+        .iter()
+        .filter(|_| user.is_none())
+        .map(|c| ("*".to_string(), c));
+    let per_user = users.into_iter().flat_map(|u| {
+        auth_options.commands[u]
+            .iter()
+            .map(move |c| (u.to_string(), c))
+    });
+    let rows = global
+        .chain(per_user)
+        .map(|(user, command)| {
+            vec![
+                user,
+                command.hook.to_string(),
+                command.name.clone().unwrap_or_default(),
+                command.timeout.map(|t| t.to_string()).unwrap_or_default(),
+                command.to_string(),
+            ]
+        })
+        .collect();
+    Listing {
+        columns: &["user", "hook", "name", "timeout", "command"],
+        rows,
+    }
+}
@@ -0,0 +1,85 @@
+use crate::config::auth_options::Username;
+use chrono::{SecondsFormat, Utc};
+use env_logger::Env;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::future::Future;
+use std::io::Write;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+tokio::task_local! {
+    static REQUEST: RefCell<RequestContext>;
+}
+
+/// Fields added to every JSON log line written while a request is handled
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    pub request_id: String,
+    pub client_ip: Option<IpAddr>,
+    pub user: Option<Username>,
+    pub auth_type: Option<String>,
+}
+
+/// Sets up the logger, `LOG_FORMAT=json` writes one JSON object per line instead of text.
+/// This runs before the config is loaded so `LOG_FORMAT` is read here.
+pub fn init() {
+    dotenv::dotenv().ok();
+    let format = dotenv::var("LOG_FORMAT").unwrap_or_default();
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    if format == "json" {
+        builder.format(|buf, record| {
+            let mut line = Map::new();
+            line.insert(
+                "timestamp".to_string(),
+                Value::from(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+            );
+            line.insert("level".to_string(), Value::from(record.level().as_str()));
+            line.insert("target".to_string(), Value::from(record.target()));
+            line.insert(
+                "message".to_string(),
+                Value::from(record.args().to_string()),
+            );
+            if let Some(request) = current() {
+                line.insert("request_id".to_string(), Value::from(request.request_id));
+                if let Some(client_ip) = request.client_ip {
+                    line.insert("client_ip".to_string(), Value::from(client_ip.to_string()));
+                }
+                if let Some(user) = request.user {
+                    line.insert("user".to_string(), Value::from(user.to_string()));
+                }
+                if let Some(auth_type) = request.auth_type {
+                    line.insert("auth_type".to_string(), Value::from(auth_type));
+                }
+            }
+            writeln!(buf, "{}", Value::Object(line))
+        });
+    }
+    builder.init();
+    if !format.is_empty() && format != "json" && format != "text" {
+        log::warn!("Unknown LOG_FORMAT {}, using text", format);
+    }
+}
+
+pub fn new_request_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Runs `f` with `context` attached to its log lines, tasks it spawns don't inherit it
+pub async fn scope<F: Future>(context: RequestContext, f: F) -> F::Output {
+    REQUEST.scope(RefCell::new(context), f).await
+}
+
+/// Changes the context of the current request, does nothing outside `scope`.
+/// `f` must not log, the context is borrowed while it runs.
+pub fn update<F: FnOnce(&mut RequestContext)>(f: F) {
+    REQUEST.try_with(|r| f(&mut r.borrow_mut())).ok();
+}
+
+/// The context of the current request, `None` outside `scope`
+pub fn current() -> Option<RequestContext> {
+    REQUEST
+        .try_with(|r| r.try_borrow().ok().map(|r| r.clone()))
+        .ok()
+        .flatten()
+}
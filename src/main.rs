@@ -2,43 +2,243 @@
 #![feature(option_result_contains)]
 mod config;
 mod error;
+mod list;
+mod logging;
 mod server;
 
+use crate::config::auth_options::AuthOptions;
 use crate::config::auth_options::Username;
-use crate::config::command::UserCommand;
+use crate::config::command::{CommandContext, Hook, UserCommand};
+use crate::config::deny_list::DenyList;
+use crate::config::redis::IpChange;
+use crate::config::secret::Secret;
+use crate::config::sqlite::SqliteStore;
 use crate::error::RauthyError;
+use crate::server::audit::{describe_deny, AuditEvent, AuditLog};
+use crate::server::commands::CommandRunner;
+use crate::server::decision::{client_ip, decide, AuthRequest};
 use crate::server::server::start;
-use clap::{App, Arg, ArgMatches};
+use chrono::{DateTime, Utc};
+use clap::{App, AppSettings, Arg, ArgMatches};
 use config::config::Config;
-use env_logger::Env;
+use ipnetwork::IpNetwork;
 use regex::Regex;
-use std::collections::HashMap;
 use std::net::IpAddr;
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 #[tokio::main]
 async fn main() -> Result<(), RauthyError> {
-    env_logger::from_env(Env::default().default_filter_or("info")).init();
+    logging::init();
     let matches = build_app();
 
     let mut config = Config::new().await?;
+    // CLI commands that change the auth file keep it locked until they exit, the server locks each access instead
+    if changes_auth_file(&matches) {
+        config.lock_auth_file().await?;
+    }
+    if let Some(matches) = matches.subcommand_matches("migrate") {
+        let database = match config.database.clone() {
+            Some(database) => database,
+            None => {
+                log::error!("AUTH_DATABASE must be set to migrate");
+                return Ok(());
+            }
+        };
+        let store = SqliteStore::new(database);
+        let version = {
+            let store = store.clone();
+            tokio::task::spawn_blocking(move || store.migrate()).await??
+        };
+        log::info!("Database schema is at version {}", version);
+
+        if let Some(json_file) = matches.value_of("from-json") {
+            let empty = tokio::task::spawn_blocking(move || store.is_empty()).await??;
+            if !empty && !matches.is_present("force") {
+                return Err(RauthyError::StoreError(format!(
+                    "The database already has entries, pass --force to replace them with {}",
+                    json_file
+                )));
+            }
+            let contents = tokio::fs::read_to_string(json_file).await?;
+            let mut auth_options = AuthOptions::from_string(contents)?;
+            auth_options.hash_legacy_passwords();
+            log::info!(
+                "Importing {} passwords, {} tokens, {} ips, {} domains and {} command lists from {}",
+                auth_options.passwords.len(),
+                auth_options.tokens.len(),
+                auth_options.ips.len(),
+                auth_options.domains.len(),
+                auth_options.commands.len(),
+                json_file
+            );
+            config.auth_options = auth_options;
+            config.write().await?;
+            audit(&config, "migrate.import", json_file.to_string());
+        }
+        return Ok(());
+    }
+
     if let Some(matches) = matches.subcommand_matches("user") {
+        if let Some(matches) = matches.subcommand_matches("list") {
+            list::users(&config.auth_options).print(json_output(matches));
+            return Ok(());
+        }
+        if let Some(matches) = matches.subcommand_matches("show") {
+            let username: Username = matches.value_of("name").unwrap().into();
+            match config.auth_options.user_details(&username, Utc::now()) {
+                Some(mut details) => {
+                    if let Some(redis) = config.connect_redis().await? {
+                        details.token_uses = Some(redis.token_usage(&username).await?);
+                    }
+                    list::user(&details, json_output(matches))
+                }
+                None => log::error!("User {} not found", username),
+            }
+            return Ok(());
+        }
         let username = matches.value_of("username").unwrap().to_string();
-        let password = matches.value_of("password").unwrap().to_string();
-        log::info!("Adding user: {}", username);
-        config
-            .auth_options
-            .remove_password_by_user(username.clone());
-        config.auth_options.add_password(username.clone(), password);
+        if matches.is_present("delete") {
+            let username: Username = username.into();
+            // The user's own commands go with the user, so they are collected before it is removed
+            let commands = config
+                .auth_options
+                .commands_for(Hook::OnIpRemoved, Some(&username));
+            let removed = match config.auth_options.remove_user(&username) {
+                Some(removed) => removed,
+                None => {
+                    log::error!("User {} not found", username);
+                    return Ok(());
+                }
+            };
+            config.write().await?;
+            audit(&config, "user.delete", format!("user {}", username));
+            if let Some(redis) = config.connect_redis().await? {
+                for ip in removed.updated_ips.iter().chain(removed.removed_ips.iter()) {
+                    redis
+                        .change(&IpChange::Revoke(*ip, username.clone()))
+                        .await?;
+                }
+            }
+            log::info!(
+                "Deleted user {} and {} IP addresses",
+                username,
+                removed.removed_ips.len()
+            );
+            for ip in removed.removed_ips {
+                run_ip_removed(&config, ip, username.clone(), &commands).await;
+            }
+            return Ok(());
+        }
+        let mut changes = vec![format!("user {}", username)];
+        if let Some(password) = matches.value_of("password") {
+            log::info!("Adding user: {}", username);
+            changes.push("password".to_string());
+            config
+                .auth_options
+                .remove_password_by_user(username.clone());
+            config
+                .auth_options
+                .add_password(username.clone(), password.to_string());
+        }
+
+        let user = config.auth_options.user_mut(&username.clone().into());
+        if matches.is_present("disable") {
+            log::info!("Disabling user: {}", username);
+            user.disabled = true;
+            changes.push("disabled true".to_string());
+        } else if matches.is_present("enable") {
+            log::info!("Enabling user: {}", username);
+            user.disabled = false;
+            changes.push("disabled false".to_string());
+        }
+        if let Some(expires) = matches.value_of("expires") {
+            user.expires_at = match expires {
+                "never" => None,
+                expires => Some(
+                    DateTime::parse_from_rfc3339(expires)
+                        .map_err(|e| RauthyError::ConfigError(format!("Invalid expiry: {}", e)))?
+                        .with_timezone(&Utc),
+                ),
+            };
+            log::info!("User {} expires at {:?}", username, user.expires_at);
+            changes.push(format!("expires_at {}", expires));
+        }
+        for meta in matches.values_of("metadata").into_iter().flatten() {
+            let mut parts = meta.splitn(2, '=');
+            let key = parts.next().unwrap_or_default().to_string();
+            changes.push(format!("metadata {}", key));
+            match parts.next() {
+                Some(value) if !value.is_empty() => {
+                    user.metadata.insert(key, value.to_string());
+                }
+                _ => {
+                    user.metadata.remove(&key);
+                }
+            }
+        }
+        let revoked = config.auth_options.revoke_inactive(Utc::now());
+        config.write().await?;
+        audit(&config, "user.update", changes.join(", "));
+        share_revoked(&config, revoked).await?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("invite") {
+        let username: Username = matches.value_of("username").unwrap().into();
+        if config.auth_options.has_password(&username) {
+            log::error!("User {} already has a password", username);
+            return Ok(());
+        }
+        let hours: i64 = matches
+            .value_of("ttl")
+            .unwrap()
+            .parse()
+            .map_err(|e| RauthyError::ConfigError(format!("Invalid ttl: {}", e)))?;
+        let groups: Vec<String> = matches
+            .values_of("group")
+            .into_iter()
+            .flatten()
+            .map(|g| g.to_string())
+            .collect();
+        let now = Utc::now();
+        let expires_at = now + chrono::Duration::hours(hours);
+        let token =
+            config
+                .auth_options
+                .create_invite(username.clone(), groups.clone(), now, expires_at);
         config.write().await?;
+        audit(
+            &config,
+            "invite.create",
+            format!(
+                "user {} groups {} expires_at {}",
+                username,
+                groups.join(","),
+                expires_at.to_rfc3339()
+            ),
+        );
+        if config.public_url.is_none() {
+            log::warn!("PUBLIC_URL is not set, prefix the invite path with rauthy's public URL");
+        }
+        log::info!("Invited {}, the link expires at {}", username, expires_at);
+        println!(
+            "{}/_rauthy/invite/{}",
+            config.public_url.clone().unwrap_or_default(),
+            token
+        );
         return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("domain") {
-        if matches.is_present("clear-domains") {
+        if let Some(matches) = matches.subcommand_matches("list") {
+            list::domains(&config.auth_options).print(json_output(matches));
+            return Ok(());
+        }
+        let (action, target) = if matches.is_present("clear-domains") {
             log::info!("Clearing all domain regexes");
             config.auth_options.clear_domain_regexes();
+            ("domain.clear", String::new())
         } else if matches.is_present("remove-domain") {
             let domain_regex = matches
                 .value_of("remove-domain")
@@ -46,96 +246,221 @@ async fn main() -> Result<(), RauthyError> {
                 .unwrap()?;
             log::info!("Removing domain regex: {:?}", domain_regex);
             config.auth_options.remove_domain_regex(&domain_regex);
+            ("domain.remove", domain_regex.to_string())
         } else if matches.is_present("add-domain") {
             let domain_regex = matches
                 .value_of("add-domain")
                 .map(|s| Regex::new(s))
                 .unwrap()?;
             log::info!("Adding domain regex: {:?}", domain_regex);
+            let target = domain_regex.to_string();
             config.auth_options.add_domain_regex(domain_regex);
+            ("domain.add", target)
         } else {
             log::error!("No parameters supplied");
             return Ok(());
-        }
+        };
 
         config.write().await?;
+        audit(&config, action, target);
 
         return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("bypass") {
-        if matches.is_present("clear-tokens") {
+        if let Some(matches) = matches.subcommand_matches("list") {
+            list::tokens(&config.auth_options).print(json_output(matches));
+            return Ok(());
+        }
+        let (action, target) = if matches.is_present("clear-tokens") {
             log::info!("Clearing tokens");
             config.auth_options.clear_tokens();
+            ("token.clear", String::new())
         } else if matches.is_present("remove-token") {
             let token = matches
                 .value_of("remove-token")
-                .map(|s| s.to_string())
+                .map(Secret::from)
                 .filter(|s| !s.is_empty())
                 .unwrap();
-            log::info!("Removing token: {:?}", token);
-            config.auth_options.remove_token(&token);
+            log::info!("Removing token: {}", token);
+            config.auth_options.remove_token(token.expose());
+            ("token.remove", token.to_string())
         } else if matches.is_present("add-token") {
-            let token = matches
-                .value_of("add-token")
-                .map(|s| s.to_string())
-                .unwrap();
+            let token = matches.value_of("add-token").map(Secret::from).unwrap();
             let username = matches.value_of("username").map(|s| s.to_string()).unwrap();
-            log::info!("Adding token: {:?}", token);
-            config.auth_options.add_token(token, username.into());
+            log::info!("Adding token: {}", token);
+            let target = format!("user {} token {}", username, token);
+            config
+                .auth_options
+                .add_token(token.into_inner(), username.into());
+            ("token.add", target)
         } else {
             log::error!("No parameters supplied");
             return Ok(());
-        }
+        };
 
         config.write().await?;
+        audit(&config, action, target);
 
         return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("cmd") {
+        if let Some(matches) = matches.subcommand_matches("list") {
+            let username: Option<Username> = matches.value_of("username").map(|u| u.into());
+            list::commands(&config.auth_options, username.as_ref()).print(json_output(matches));
+            return Ok(());
+        }
         if matches.is_present("clear") {
             let username = matches.value_of("username").map(|s| s.to_string().into());
             config.auth_options.remove_all_commands(username.clone());
-            log::info!(
-                "Clearing commands for {}",
-                username.unwrap_or("All Users".to_string().into())
-            );
+            let username = username.unwrap_or("All Users".to_string().into());
+            log::info!("Clearing commands for {}", username);
             config.write().await?;
+            audit(&config, "command.clear", format!("user {}", username));
             return Ok(());
         }
-        let username: Username = matches.value_of("username").unwrap().to_string().into();
+        let username: Option<Username> = matches.value_of("username").map(|u| u.into());
+        let hook = matches
+            .value_of("hook")
+            .map(|h| h.parse::<Hook>())
+            .transpose()?
+            .unwrap_or_default();
         let path = matches.value_of("path").map(|s| s.to_string());
         let command = matches.value_of("command").unwrap().to_string();
         let name = matches.value_of("name").map(|s| s.to_string());
+        let args = matches
+            .values_of("args")
+            .map(|a| a.map(|s| s.to_string()).collect())
+            .unwrap_or_default();
+        let shell = matches.is_present("shell");
+        let timeout = matches
+            .value_of("timeout")
+            .map(|t| t.parse::<u64>())
+            .transpose()
+            .map_err(|e| RauthyError::ConfigError(format!("Invalid timeout: {}", e)))?;
 
-        log::info!(
-            "Adding command for user: {} called: {:?} - `cd {:?} && {}`",
-            username,
+        let user_command = UserCommand {
             name,
             path,
-            command
+            command,
+            args,
+            shell,
+            timeout,
+            hook,
+        };
+        log::info!(
+            "Adding {} command for user: {} called: {:?} - `{}`",
+            hook,
+            username.clone().unwrap_or("All Users".to_string().into()),
+            user_command.name,
+            user_command
         );
-        config.auth_options.add_command(
-            &username,
-            UserCommand {
-                name,
-                path,
-                command,
-            },
+        let target = format!(
+            "user {} {} command {}",
+            username.clone().unwrap_or("All Users".to_string().into()),
+            hook,
+            user_command
         );
+        match username {
+            Some(username) => config.auth_options.add_command(&username, user_command),
+            None => config.auth_options.add_global_command(user_command),
+        }
+        config.write().await?;
+        audit(&config, "command.add", target);
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("deny") {
+        if matches.is_present("clear") {
+            log::info!("Clearing denied IPs and tokens");
+            config.auth_options.deny = DenyList::default();
+            config.write().await?;
+            audit(&config, "deny.clear", String::new());
+            return Ok(());
+        }
+        let entries = DenyList {
+            ips: matches
+                .values_of("ip")
+                .map(|ips| ips.map(|ip| ip.parse::<IpNetwork>()).collect())
+                .transpose()
+                .map_err(|e| RauthyError::ConfigError(format!("Invalid IP or CIDR: {}", e)))?
+                .unwrap_or_default(),
+            users: matches
+                .values_of("username")
+                .map(|u| u.map(|u| u.into()).collect())
+                .unwrap_or_default(),
+            tokens: matches
+                .values_of("token")
+                .map(|t| t.map(Secret::from).collect())
+                .unwrap_or_default(),
+        };
+        let target = describe_deny(&entries);
+        let action = if matches.is_present("remove") {
+            log::info!("Removing from deny list: {:?}", entries);
+            config.auth_options.remove_deny(&entries);
+            "deny.remove"
+        } else {
+            log::info!("Adding to deny list: {:?}", entries);
+            config.auth_options.add_deny(entries);
+            "deny.add"
+        };
+        let revoked = config.auth_options.revoke_inactive(Utc::now());
         config.write().await?;
+        audit(&config, action, target);
+        share_revoked(&config, revoked).await?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("check") {
+        let request = check_request(matches)?;
+        let decision = decide(
+            &config.auth_options,
+            config.ignore_ip,
+            &request,
+            Utc::now(),
+            true,
+        );
+        if json_output(matches) {
+            println!("{}", serde_json::to_string_pretty(&decision)?);
+        } else {
+            println!("Result: {:?}", decision.authorized);
+            if let Some(user) = decision.user.as_ref() {
+                println!("User: {}", user);
+            }
+            if let Some(reason) = decision.denied.as_ref() {
+                println!("Denied: {}", reason);
+            }
+            println!("Evaluated:");
+            for step in decision.trace.iter() {
+                println!("  {}", step);
+            }
+        }
         return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("ip") {
+        if let Some(matches) = matches.subcommand_matches("list") {
+            let username: Option<Username> = matches.value_of("username").map(|u| u.into());
+            list::ips(&config.auth_options, username.as_ref()).print(json_output(matches));
+            return Ok(());
+        }
+        let redis = config.connect_redis().await?;
         if matches.is_present("clear") {
             log::info!(
                 "Clearing all {} IP addresses",
                 config.auth_options.ips.len()
             );
-            config.auth_options.ips = HashMap::new();
+            let ips = config.auth_options.ips.clone();
+            config.auth_options.clear_ips();
             config.write().await?;
+            audit(&config, "ip.clear", String::new());
+            if let Some(redis) = redis {
+                redis.clear_ips().await?;
+            }
+            for (ip, users) in ips {
+                fire_ip_removed(&config, ip, users).await;
+            }
             return Ok(());
         } else if matches.is_present("add") {
             let ip = matches
@@ -149,6 +474,10 @@ async fn main() -> Result<(), RauthyError> {
 
             config.auth_options.add_ip_and_user(ip, username.as_ref());
             config.write().await?;
+            audit(&config, "ip.add", format!("ip {} user {:?}", ip, username));
+            if let Some(redis) = redis {
+                redis.change(&IpChange::Grant(ip, username.clone())).await?;
+            }
             log::info!("Adding ip: {} for username: {:?}", ip, username);
             return Ok(());
         } else if matches.is_present("delete") {
@@ -156,9 +485,20 @@ async fn main() -> Result<(), RauthyError> {
                 .value_of("delete")
                 .map(|ip| ip.parse::<IpAddr>().unwrap())
                 .unwrap();
+            let users = config
+                .auth_options
+                .ips
+                .get(&ip)
+                .cloned()
+                .unwrap_or_default();
             config.auth_options.remove_ip(&ip);
             config.write().await?;
+            audit(&config, "ip.remove", format!("ip {}", ip));
+            if let Some(redis) = redis {
+                redis.change(&IpChange::Remove(ip)).await?;
+            }
             log::info!("Removed IP address {}", ip);
+            fire_ip_removed(&config, ip, users).await;
             return Ok(());
         }
     }
@@ -167,6 +507,158 @@ async fn main() -> Result<(), RauthyError> {
     Ok(())
 }
 
+/// Records a change made from the CLI in the audit log
+fn audit(config: &Config, action: &str, target: String) {
+    match AuditLog::open(config) {
+        Ok(Some(audit)) => {
+            audit.record(AuditEvent::Admin {
+                source: "cli",
+                action: action.to_string(),
+                target,
+            });
+            audit.close();
+        }
+        Ok(None) => {}
+        Err(e) => log::error!("Unable to open the audit log: {}", e),
+    }
+}
+
+/// Shares users `AuthOptions::revoke_inactive` took off IP grants and fires `on_ip_removed` for dropped IPs
+async fn share_revoked(
+    config: &Config,
+    revoked: Vec<(IpAddr, Username, bool)>,
+) -> Result<(), RauthyError> {
+    if revoked.is_empty() {
+        return Ok(());
+    }
+    if let Some(redis) = config.connect_redis().await? {
+        for (ip, username, _) in revoked.iter() {
+            redis
+                .change(&IpChange::Revoke(*ip, username.clone()))
+                .await?;
+        }
+    }
+    for (ip, username, ip_removed) in revoked {
+        log::info!("Took inactive user {} off IP {}", username, ip);
+        if ip_removed {
+            fire_ip_removed(config, ip, vec![username]).await;
+        }
+    }
+    Ok(())
+}
+
+async fn fire_ip_removed(config: &Config, ip: IpAddr, users: Vec<Username>) {
+    let users = if users.is_empty() {
+        vec![Username::default()]
+    } else {
+        users
+    };
+    for user in users {
+        let commands = config
+            .auth_options
+            .commands_for(Hook::OnIpRemoved, Some(&user));
+        run_ip_removed(config, ip, user, &commands).await;
+    }
+}
+
+async fn run_ip_removed(config: &Config, ip: IpAddr, user: Username, commands: &[UserCommand]) {
+    let runner = CommandRunner::new(config.command_concurrency, config.command_timeout);
+    let context = CommandContext {
+        hook: Hook::OnIpRemoved,
+        user,
+        ip: Some(ip),
+        ..CommandContext::default()
+    };
+    runner.run_all(&context, commands).await;
+}
+
+/// Builds the request `rauthy check` evaluates, the same way the server reads it from HTTP
+fn check_request(matches: &ArgMatches) -> Result<AuthRequest, RauthyError> {
+    let mut headers: Vec<(String, String)> = vec![];
+    for header in matches.values_of("header").into_iter().flatten() {
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap_or_default().trim().to_lowercase();
+        match parts.next() {
+            Some(value) => headers.push((name, value.trim().to_string())),
+            None => {
+                return Err(RauthyError::ConfigError(format!(
+                    "Invalid header {}, expected 'Name: value'",
+                    header
+                )))
+            }
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.clone())
+    };
+
+    let client_ip = match matches.value_of("ip") {
+        Some(ip) => Some(
+            ip.parse::<IpAddr>()
+                .map_err(|e| RauthyError::ConfigError(format!("Invalid IP {}: {}", ip, e)))?,
+        ),
+        None => client_ip(
+            header("http-client-ip").as_deref(),
+            header("x-forwarded-for").as_deref(),
+        ),
+    };
+    let path = matches.value_of("path").unwrap_or("/");
+    let mut parts = path.splitn(2, '?');
+    let path = parts.next().unwrap_or_default();
+    let token = parts
+        .next()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find(|param| param.starts_with("token="))
+        .map(|param| param["token=".len()..].to_string());
+    Ok(AuthRequest {
+        client_ip,
+        auth_header: header("authorization").map(Secret::from),
+        bypass_token_header: header("x-bypass-token").map(Secret::from),
+        bypass_token_query: token.map(Secret::from),
+        path: path.trim_start_matches('/').to_string(),
+        host: matches
+            .value_of("host")
+            .map(|h| h.to_string())
+            .or_else(|| header("host")),
+        user_agent: header("user-agent"),
+    })
+}
+
+fn json_output(matches: &ArgMatches) -> bool {
+    matches.value_of("output") == Some("json")
+}
+
+fn output_arg() -> Arg<'static> {
+    Arg::with_name("output")
+        .short('o')
+        .long("output")
+        .takes_value(true)
+        .possible_values(&["table", "json"])
+        .default_value("table")
+        .about("Output format")
+}
+
+/// A `list` subcommand printing a table or JSON
+/// Whether the subcommand writes the auth file, `check`, `list` and `show` only read it
+fn changes_auth_file(matches: &ArgMatches) -> bool {
+    let name = match matches.subcommand_name() {
+        Some(name) => name,
+        None => return false,
+    };
+    let sub = matches
+        .subcommand_matches(name)
+        .and_then(|m| m.subcommand_name());
+    name != "check" && !matches!(sub, Some("list") | Some("show"))
+}
+
+fn list_app(about: &'static str) -> App<'static> {
+    App::new("list").about(about).arg(output_arg())
+}
+
 fn build_app() -> ArgMatches {
     App::new("rauthy")
         .version(VERSION)
@@ -174,7 +666,22 @@ fn build_app() -> ArgMatches {
         .about("An auth proxy service")
         .subcommand(
             App::new("user")
-                .about("Add basic auth users")
+                .about("Add basic auth users and manage their accounts")
+                .setting(AppSettings::SubcommandsNegateReqs)
+                .subcommand(list_app(
+                    "List users with their status and credential counts",
+                ))
+                .subcommand(
+                    App::new("show")
+                        .about("Show a user's details and login history")
+                        .arg(
+                            Arg::with_name("name")
+                                .required(true)
+                                .index(1)
+                                .about("The username"),
+                        )
+                        .arg(output_arg()),
+                )
                 .arg(
                     Arg::with_name("username")
                         .short('u')
@@ -185,14 +692,82 @@ fn build_app() -> ArgMatches {
                 .arg(
                     Arg::with_name("password")
                         .short('p')
-                        .required(true)
+                        .required_unless_one(&[
+                            "disable", "enable", "expires", "metadata", "delete",
+                        ])
                         .takes_value(true)
                         .about("Adds a password for basic auth"),
+                )
+                .arg(
+                    Arg::with_name("delete")
+                        .long("delete")
+                        .conflicts_with_all(&[
+                            "password", "disable", "enable", "expires", "metadata",
+                        ])
+                        .about(
+                            "Delete the user with their passwords, tokens, IP grants and commands",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("disable")
+                        .long("disable")
+                        .conflicts_with("enable")
+                        .about("Disable the user, refusing their passwords, tokens and IP grants"),
+                )
+                .arg(
+                    Arg::with_name("enable")
+                        .long("enable")
+                        .about("Enable a disabled user"),
+                )
+                .arg(
+                    Arg::with_name("expires")
+                        .short('x')
+                        .long("expires")
+                        .takes_value(true)
+                        .about("When the user expires as an RFC 3339 timestamp, or `never`"),
+                )
+                .arg(
+                    Arg::with_name("metadata")
+                        .short('m')
+                        .long("meta")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .about("Set metadata as key=value, an empty value removes the key"),
+                ),
+        )
+        .subcommand(
+            App::new("invite")
+                .about("Print a single-use link where a new user sets their own password")
+                .arg(
+                    Arg::with_name("username")
+                        .short('u')
+                        .required(true)
+                        .takes_value(true)
+                        .about("The username to invite"),
+                )
+                .arg(
+                    Arg::with_name("group")
+                        .short('g')
+                        .long("groups")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .about("Add the user to a group, kept in their groups metadata"),
+                )
+                .arg(
+                    Arg::with_name("ttl")
+                        .short('t')
+                        .long("ttl")
+                        .takes_value(true)
+                        .default_value("72")
+                        .about("Hours before the link expires"),
                 ),
         )
         .subcommand(
             App::new("domain")
                 .about("Add a domain regex to bypass auth")
+                .subcommand(list_app("List the domain regexes that bypass auth"))
                 .arg(
                     Arg::with_name("remove-domain")
                         .short('r')
@@ -214,6 +789,8 @@ fn build_app() -> ArgMatches {
         .subcommand(
             App::new("bypass")
                 .about("Manage bypass tokens")
+                .setting(AppSettings::SubcommandsNegateReqs)
+                .subcommand(list_app("List bypass tokens, redacted"))
                 .arg(
                     Arg::with_name("username")
                         .short('u')
@@ -242,12 +819,29 @@ fn build_app() -> ArgMatches {
         .subcommand(
             App::new("cmd")
                 .about("Add a command for a user")
+                .setting(AppSettings::SubcommandsNegateReqs)
+                .subcommand(
+                    list_app("List commands, global commands are shown for user *").arg(
+                        Arg::with_name("username")
+                            .short('u')
+                            .long("user")
+                            .takes_value(true)
+                            .about("Only list commands for this user"),
+                    ),
+                )
                 .arg(
                     Arg::with_name("username")
                         .short('u')
-                        .required_unless("clear")
                         .takes_value(true)
-                        .about("Adds a command for this username"),
+                        .about("Adds a command for this username, omit to run it for every user"),
+                )
+                .arg(
+                    Arg::with_name("hook")
+                        .short('H')
+                        .long("hook")
+                        .takes_value(true)
+                        .possible_values(Hook::NAMES)
+                        .about("When to run the command, defaults to on_login"),
                 )
                 .arg(
                     Arg::with_name("name")
@@ -269,6 +863,26 @@ fn build_app() -> ArgMatches {
                         .takes_value(true)
                         .about("The path for command execution"),
                 )
+                .arg(
+                    Arg::with_name("args")
+                        .short('a')
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .about("An argument for the command, repeat for each argument"),
+                )
+                .arg(
+                    Arg::with_name("shell")
+                        .short('s')
+                        .long("shell")
+                        .about("Run the command with `sh -c` so it can use pipes and quoting"),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .short('t')
+                        .takes_value(true)
+                        .about("Seconds before the command is killed, defaults to COMMAND_TIMEOUT"),
+                )
                 .arg(
                     Arg::with_name("clear").short('C').about(
                         "Clear all commands for this user if supplied otherwise all commands",
@@ -278,6 +892,16 @@ fn build_app() -> ArgMatches {
         .subcommand(
             App::new("ip")
                 .about("Manage ip addresses")
+                .setting(AppSettings::SubcommandsNegateReqs)
+                .subcommand(
+                    list_app("List authorized IP addresses").arg(
+                        Arg::with_name("username")
+                            .short('u')
+                            .long("user")
+                            .takes_value(true)
+                            .about("Only list IPs granted to this user"),
+                    ),
+                )
                 .arg(
                     Arg::with_name("delete")
                         .short('d')
@@ -305,5 +929,89 @@ fn build_app() -> ArgMatches {
                         .about("Clear all IP addresses"),
                 ),
         )
+        .subcommand(
+            App::new("deny")
+                .about("Refuse IPs, users and tokens before any other auth")
+                .arg(
+                    Arg::with_name("ip")
+                        .short('i')
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required_unless_one(&["username", "token", "clear"])
+                        .about("An IP address or CIDR range to deny"),
+                )
+                .arg(
+                    Arg::with_name("username")
+                        .short('u')
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .about("A username to disable"),
+                )
+                .arg(
+                    Arg::with_name("token")
+                        .short('t')
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .about("A bypass token to revoke"),
+                )
+                .arg(
+                    Arg::with_name("remove").short('r').about(
+                        "Remove the given entries from the deny list instead of adding them",
+                    ),
+                )
+                .arg(
+                    Arg::with_name("clear")
+                        .short('C')
+                        .about("Clear denied IPs and tokens, disabled users stay disabled"),
+                ),
+        )
+        .subcommand(
+            App::new("check")
+                .about("Show how the server would decide a request, without changing anything")
+                .arg(Arg::with_name("ip").long("ip").takes_value(true).about(
+                    "The client IP, defaults to the http-client-ip or x-forwarded-for header",
+                ))
+                .arg(
+                    Arg::with_name("host")
+                        .long("host")
+                        .takes_value(true)
+                        .about("The requested host, defaults to the host header"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .about("The requested path including any ?token= query"),
+                )
+                .arg(
+                    Arg::with_name("header")
+                        .short('H')
+                        .long("header")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .about("A request header as 'Name: value', repeat for each header"),
+                )
+                .arg(output_arg()),
+        )
+        .subcommand(
+            App::new("migrate")
+                .about("Apply database migrations for AUTH_DATABASE")
+                .arg(
+                    Arg::with_name("from-json")
+                        .long("from-json")
+                        .takes_value(true)
+                        .about("Import an existing auth json file into the database"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .requires("from-json")
+                        .about("Replace the entries of a database that isn't empty"),
+                ),
+        )
         .get_matches()
 }
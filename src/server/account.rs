@@ -0,0 +1,335 @@
+use crate::config::auth_options::Username;
+use crate::config::command::{CommandContext, Hook};
+use crate::config::invite;
+use crate::config::redis::IpChange;
+use crate::config::secret::Secret;
+use crate::config::totp;
+use crate::error::RauthyError;
+use crate::server::audit::AuditEvent;
+use crate::server::lockout::Lockout;
+use crate::server::state::State;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::IpAddr;
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::Reply;
+
+/// Served on `GET /_rauthy/account/password`, posts the form back to the same path
+pub const PASSWORD_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Change password</title></head>
+<body>
+<h1>Change password</h1>
+<form method="post" action="/_rauthy/account/password">
+<p><label>Username <input name="username" autocomplete="username" required></label></p>
+<p><label>Current password <input name="current_password" type="password" autocomplete="current-password" required></label></p>
+<p><label>Authenticator code <input name="totp_code" inputmode="numeric" autocomplete="one-time-code" pattern="[0-9]{6}"></label> if you enrolled one</p>
+<p><label>New password <input name="new_password" type="password" autocomplete="new-password" required></label></p>
+<p><label><input name="revoke_ips" type="checkbox" value="true"> Sign out everywhere else</label></p>
+<p><button type="submit">Change password</button></p>
+</form>
+</body>
+</html>
+"#;
+
+#[derive(Deserialize)]
+pub struct ChangePassword {
+    pub username: String,
+    pub current_password: Secret,
+    /// Required for users enrolled in TOTP, unless it follows `current_password` as it does for Basic auth
+    #[serde(default)]
+    pub totp_code: String,
+    pub new_password: Secret,
+    /// Take the user off every IP grant except the one the change came from
+    #[serde(default)]
+    pub revoke_ips: bool,
+}
+
+fn refuse(status: StatusCode, error: &str) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(&json!({ "error": error })), status).into_response()
+}
+
+/// Replaces the user's passwords after checking the current one and the password policy
+pub async fn change_password(
+    change: ChangePassword,
+    client_ip: Option<IpAddr>,
+    state: Arc<State>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let username: Username = change.username.trim().into();
+    // Failures count against both the IP and the user, so guessing from many IPs is slowed down too
+    let lockout_keys: Vec<String> = client_ip
+        .map(Lockout::ip_key)
+        .into_iter()
+        .chain(std::iter::once(Lockout::user_key(&username)))
+        .collect();
+    if state.lockout.is_locked(&lockout_keys).await {
+        log::info!(
+            "Refused password change for locked out {} from {:?}",
+            username,
+            client_ip
+        );
+        state
+            .metrics
+            .failures
+            .with_label_values(&["locked_out"])
+            .inc();
+        return Ok(refuse(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many failed attempts, try again later",
+        ));
+    }
+    let auth_options = state.auth_options();
+    let now = Utc::now();
+    let active = auth_options.user_status(&username, now) == "active";
+    // Checking the bcrypt hash is slow, so it runs off the async workers
+    let current = {
+        let username = username.clone();
+        let password = format!(
+            "{}{}",
+            change.current_password.expose(),
+            change.totp_code.trim()
+        );
+        tokio::task::spawn_blocking(move || auth_options.check_password(&username, &password, now))
+            .await
+            .map_err(RauthyError::from)?
+    };
+    if !active || !current {
+        log::info!(
+            "Refused password change for {} from {:?}",
+            username,
+            client_ip
+        );
+        state
+            .metrics
+            .failures
+            .with_label_values(&["invalid_credentials"])
+            .inc();
+        let context = CommandContext {
+            ip: client_ip,
+            auth_type: "PasswordChange".to_string(),
+            path: "/_rauthy/account/password".to_string(),
+            ..CommandContext::default()
+        };
+        state.fire(Hook::OnAuthFailure, context.clone());
+        state.record_failure(&lockout_keys, context).await;
+        return Ok(refuse(
+            StatusCode::UNAUTHORIZED,
+            "Invalid username or password",
+        ));
+    }
+    if change.new_password == change.current_password {
+        return Ok(refuse(
+            StatusCode::BAD_REQUEST,
+            "The new password must be different",
+        ));
+    }
+    let policy = state.config.password_policy.clone();
+    let (name, password) = (username.to_string(), change.new_password.clone());
+    let violation = tokio::task::spawn_blocking(move || policy.violation(&name, &password))
+        .await
+        .map_err(RauthyError::from)??;
+    if let Some(violation) = violation {
+        return Ok(refuse(StatusCode::BAD_REQUEST, &violation));
+    }
+
+    let new_password = change.new_password.into_inner();
+    let revoke_ips = change.revoke_ips;
+    let revoked = state
+        .modify(|auth_options| {
+            auth_options.remove_password_by_user(username.to_string());
+            auth_options.add_password(username.to_string(), new_password);
+            if !revoke_ips {
+                return vec![];
+            }
+            let ips: Vec<IpAddr> = auth_options
+                .ips
+                .iter()
+                .filter(|(ip, users)| Some(**ip) != client_ip && users.contains(&username))
+                .map(|(ip, _)| *ip)
+                .collect();
+            ips.into_iter()
+                .filter_map(|ip| {
+                    auth_options
+                        .revoke_ip(&ip, Some(&username))
+                        .map(|(_, removed)| (ip, removed))
+                })
+                .collect()
+        })
+        .await?;
+    log::info!(
+        "User {} changed their password, revoked {} IP grants",
+        username,
+        revoked.len()
+    );
+    state.audit(AuditEvent::Admin {
+        source: "user",
+        action: "user.password".to_string(),
+        target: format!("user {} revoked_ips {}", username, revoked.len()),
+    });
+
+    let changes: Vec<IpChange> = revoked
+        .iter()
+        .map(|(ip, _)| IpChange::Revoke(*ip, username.clone()))
+        .collect();
+    state.share_ips(&changes).await;
+    for (ip, _) in revoked.iter().filter(|(_, removed)| *removed) {
+        let context = CommandContext {
+            user: username.clone(),
+            ip: Some(*ip),
+            ..CommandContext::default()
+        };
+        state.fire(Hook::OnIpRemoved, context);
+    }
+    Ok(warp::reply::json(&json!({ "changed": true, "revoked_ips": revoked.len() })).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct AcceptInvite {
+    pub password: Secret,
+    /// A code from the authenticator app the invitee enrolled with the invite's TOTP secret
+    pub totp_code: String,
+}
+
+pub fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Served on `GET /_rauthy/invite/{token}`, lets the invitee pick their password and enroll their authenticator app
+pub async fn invite_page(
+    token: String,
+    state: Arc<State>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let auth_options = state.auth_options();
+    let invite = auth_options
+        .invite(&token, Utc::now())
+        .filter(|invite| !auth_options.has_password(&invite.username));
+    let invite = match invite {
+        Some(invite) => invite,
+        None => {
+            let page = "<!DOCTYPE html>\n<html><body><p>This invite is invalid or has expired.</p></body></html>\n";
+            return Ok(
+                warp::reply::with_status(warp::reply::html(page), StatusCode::NOT_FOUND)
+                    .into_response(),
+            );
+        }
+    };
+    let totp_secret = invite::totp_secret(&token);
+    let page = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Welcome</title></head>
+<body>
+<h1>Welcome {username}</h1>
+<p>Add this key to your authenticator app, or open <a href="{totp_uri}">this link</a> on the device it runs on:</p>
+<p><code>{totp_secret}</code></p>
+<p>When you log in, type the app's current code right after your password.</p>
+<form method="post" action="/_rauthy/invite/{token}">
+<input type="hidden" name="username" value="{username}" autocomplete="username">
+<p><label>Password <input name="password" type="password" autocomplete="new-password" required></label></p>
+<p><label>Authenticator code <input name="totp_code" inputmode="numeric" autocomplete="one-time-code" pattern="[0-9]{{6}}" required></label></p>
+<p><button type="submit">Set password</button></p>
+</form>
+</body>
+</html>
+"#,
+        username = escape_html(&invite.username.to_string()),
+        token = escape_html(&token),
+        totp_secret = totp_secret,
+        totp_uri = escape_html(&totp::uri(&totp_secret, &invite.username.to_string())),
+    );
+    Ok(warp::reply::html(page).into_response())
+}
+
+/// Sets the invitee's password and TOTP secret once their code checks out and burns the invite
+pub async fn accept_invite(
+    token: String,
+    accept: AcceptInvite,
+    client_ip: Option<IpAddr>,
+    state: Arc<State>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    // Guessing invite tokens counts towards the same lockout as guessing passwords
+    let lockout_keys: Vec<String> = client_ip.map(Lockout::ip_key).into_iter().collect();
+    if state.lockout.is_locked(&lockout_keys).await {
+        log::info!("Refused invite from locked out {:?}", client_ip);
+        return Ok(refuse(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many failed attempts, try again later",
+        ));
+    }
+    let now = Utc::now();
+    let auth_options = state.auth_options();
+    let username = match auth_options.invite(&token, now) {
+        Some(invite) => invite.username.clone(),
+        None => {
+            log::info!("Refused invalid invite from {:?}", client_ip);
+            state
+                .metrics
+                .failures
+                .with_label_values(&["invalid_invite"])
+                .inc();
+            let context = CommandContext {
+                ip: client_ip,
+                auth_type: "Invite".to_string(),
+                path: "/_rauthy/invite".to_string(),
+                ..CommandContext::default()
+            };
+            state.fire(Hook::OnAuthFailure, context.clone());
+            state.record_failure(&lockout_keys, context).await;
+            return Ok(refuse(
+                StatusCode::NOT_FOUND,
+                "This invite is invalid or has expired",
+            ));
+        }
+    };
+    if auth_options.user_status(&username, now) != "active" {
+        return Ok(refuse(StatusCode::FORBIDDEN, "This user may not log in"));
+    }
+    if auth_options.has_password(&username) {
+        return Ok(refuse(
+            StatusCode::CONFLICT,
+            "This user already has a password",
+        ));
+    }
+    // The invite is only burned once the password is accepted, so the invitee can retry
+    let policy = state.config.password_policy.clone();
+    let (name, password) = (username.to_string(), accept.password.clone());
+    let violation = tokio::task::spawn_blocking(move || policy.violation(&name, &password))
+        .await
+        .map_err(RauthyError::from)??;
+    if let Some(violation) = violation {
+        return Ok(refuse(StatusCode::BAD_REQUEST, &violation));
+    }
+    if !totp::verify(&invite::totp_secret(&token), accept.totp_code.trim(), now) {
+        return Ok(refuse(
+            StatusCode::BAD_REQUEST,
+            "The authenticator code is not valid, check the app's clock and try the current code",
+        ));
+    }
+
+    let password = accept.password.into_inner();
+    let accepted = state
+        .modify(|auth_options| auth_options.accept_invite(&token, password, now))
+        .await?;
+    let username = match accepted {
+        Some(username) => username,
+        None => {
+            return Ok(refuse(
+                StatusCode::NOT_FOUND,
+                "This invite is invalid or has expired",
+            ))
+        }
+    };
+    log::info!("User {} accepted their invite", username);
+    state.audit(AuditEvent::Admin {
+        source: "user",
+        action: "invite.accept".to_string(),
+        target: format!("user {}", username),
+    });
+    Ok(warp::reply::json(&json!({ "username": username.to_string() })).into_response())
+}
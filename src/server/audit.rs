@@ -0,0 +1,186 @@
+use crate::config::auth_options::Username;
+use crate::config::config::Config;
+use crate::config::deny_list::DenyList;
+use crate::error::RauthyError;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::JoinHandle;
+
+/// One line of the audit log
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// The outcome of an auth request
+    Decision {
+        client_ip: Option<IpAddr>,
+        host: Option<String>,
+        path: String,
+        auth_type: String,
+        user: Option<Username>,
+        outcome: &'static str,
+        reason: Option<String>,
+    },
+    /// A change made through the API or the CLI, credentials in `target` are fingerprints
+    Admin {
+        source: &'static str,
+        action: String,
+        target: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct AuditRecord {
+    timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    event: AuditEvent,
+}
+
+/// Lists the entries of `deny`, tokens are shown as fingerprints
+pub fn describe_deny(deny: &DenyList) -> String {
+    let entries: Vec<String> = deny
+        .ips
+        .iter()
+        .map(|n| format!("ip {}", n))
+        .chain(deny.users.iter().map(|u| format!("user {}", u)))
+        .chain(deny.tokens.iter().map(|t| format!("token {}", t)))
+        .collect();
+    entries.join(", ")
+}
+
+/// Append-only JSON lines log of auth decisions and admin changes.
+/// Records are written on a background thread so requests never wait on the disk.
+pub struct AuditLog {
+    records: Option<Sender<AuditRecord>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AuditLog {
+    /// Opens `AUDIT_LOG`, `None` if it is not set
+    pub fn open(config: &Config) -> Result<Option<Self>, RauthyError> {
+        let destination = match config.audit_log.as_ref() {
+            Some(destination) => destination,
+            None => return Ok(None),
+        };
+        let mut writer = if destination == "-" || destination == "stdout" {
+            Writer::stdout()
+        } else {
+            Writer::file(
+                PathBuf::from(destination),
+                config.audit_log_max_size,
+                config.audit_log_backups,
+            )?
+        };
+
+        let (records, rx) = channel::<AuditRecord>();
+        let writer = std::thread::spawn(move || {
+            for record in rx {
+                let result = serde_json::to_string(&record)
+                    .map_err(RauthyError::from)
+                    .and_then(|line| Ok(writer.write(&line)?));
+                if let Err(e) = result {
+                    log::error!("Failed to write audit record {:?}: {}", record, e);
+                }
+            }
+        });
+        Ok(Some(Self {
+            records: Some(records),
+            writer: Some(writer),
+        }))
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        let record = AuditRecord {
+            timestamp: Utc::now(),
+            event,
+        };
+        if let Some(records) = self.records.as_ref() {
+            if let Err(e) = records.send(record) {
+                log::error!("Audit log closed, dropping {:?}", e.0);
+            }
+        }
+    }
+
+    /// Waits for every record to be written
+    pub fn close(mut self) {
+        self.records.take();
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
+
+struct Writer {
+    path: Option<PathBuf>,
+    file: Option<File>,
+    size: u64,
+    max_size: u64,
+    backups: usize,
+}
+
+impl Writer {
+    fn stdout() -> Self {
+        Self {
+            path: None,
+            file: None,
+            size: 0,
+            max_size: 0,
+            backups: 0,
+        }
+    }
+
+    fn file(path: PathBuf, max_size: u64, backups: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: Some(path),
+            file: Some(file),
+            size,
+            max_size,
+            backups,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        let line = format!("{}\n", line);
+        let path = match self.path.clone() {
+            Some(path) => path,
+            None => return io::stdout().write_all(line.as_bytes()),
+        };
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate(&path)?;
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(line.as_bytes())?;
+            self.size += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Shifts `<path>.1` .. `<path>.N` along, moves the current log to `<path>.1` and starts a new one
+    fn rotate(&mut self, path: &PathBuf) -> io::Result<()> {
+        let sibling = |index: usize| {
+            let mut name = path.file_name().unwrap_or_default().to_os_string();
+            name.push(format!(".{}", index));
+            path.with_file_name(name)
+        };
+        self.file.take();
+        if self.backups == 0 {
+            fs::remove_file(path)?;
+        } else {
+            for index in (1..self.backups).rev() {
+                if sibling(index).exists() {
+                    fs::rename(sibling(index), sibling(index + 1))?;
+                }
+            }
+            fs::rename(path, sibling(1))?;
+        }
+        self.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        self.size = 0;
+        Ok(())
+    }
+}
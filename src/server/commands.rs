@@ -0,0 +1,86 @@
+use crate::config::command::{CommandContext, UserCommand};
+use crate::error::RauthyError;
+use crate::server::metrics::Metrics;
+use crate::server::telemetry::Span;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// Runs user commands in the background, at most `concurrency` at a time
+#[derive(Clone)]
+pub struct CommandRunner {
+    permits: Arc<Semaphore>,
+    timeout: Duration,
+    metrics: Option<Metrics>,
+}
+
+impl CommandRunner {
+    pub fn new(concurrency: usize, timeout: Duration) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+            timeout,
+            metrics: None,
+        }
+    }
+
+    /// Counts every run in `command_executions_total`
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Runs `commands` in order without waiting for them to finish
+    pub fn spawn(&self, context: CommandContext, commands: Vec<UserCommand>) {
+        if commands.is_empty() {
+            return;
+        }
+        let runner = self.clone();
+        tokio::spawn(async move { runner.run_all(&context, &commands).await });
+    }
+
+    /// Runs `commands` in order and waits for all of them
+    pub async fn run_all(&self, context: &CommandContext, commands: &[UserCommand]) {
+        for command in commands {
+            self.run(context, command).await;
+        }
+    }
+
+    async fn run(&self, context: &CommandContext, command: &UserCommand) {
+        let _permit = self.permits.acquire().await;
+        let username = &context.user;
+        let span = Span::start("command.run");
+        span.set("command", command);
+        span.set("hook", context.hook);
+        span.set("user", username);
+        log::debug!("Executing command {} for {}", command, username);
+        let result = command.run(context, self.timeout).await;
+        let label = match &result {
+            Ok(output) if output.status.success() => "success",
+            Ok(_) => "failure",
+            Err(RauthyError::CommandTimeout(_)) => "timeout",
+            Err(_) => "error",
+        };
+        span.set("result", label);
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.commands.with_label_values(&[label]).inc();
+        }
+        match result {
+            Ok(output) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if output.status.success() {
+                    log::info!("Command {} for {} {}", command, username, output.status);
+                } else {
+                    log::warn!("Command {} for {} {}", command, username, output.status);
+                }
+                if !stdout.trim().is_empty() {
+                    log::debug!("Command {} stdout: {}", command, stdout.trim());
+                }
+                if !stderr.trim().is_empty() {
+                    log::debug!("Command {} stderr: {}", command, stderr.trim());
+                }
+            }
+            Err(e) => log::error!("Command {} for {} failed: {}", command, username, e),
+        }
+    }
+}
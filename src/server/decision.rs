@@ -0,0 +1,397 @@
+use crate::config::auth_options::{AuthOptions, Username};
+use crate::config::secret::Secret;
+use crate::server::decision::AuthenticationType::{
+    BasicAuth, BypassTokenHeader, BypassTokenPath, BypassTokenQuery, ClientIp, Denied, DomainRegex,
+    Unauthenticated,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// The parts of a request the auth decision depends on
+#[derive(Debug, Clone, Default)]
+pub struct AuthRequest {
+    pub client_ip: Option<IpAddr>,
+    pub auth_header: Option<Secret>,
+    pub bypass_token_header: Option<Secret>,
+    pub bypass_token_query: Option<Secret>,
+    /// The request path without the leading `/`, the last segment may be a token so use `redacted_path` to log it
+    pub path: String,
+    pub host: Option<String>,
+    /// Only recorded in the login history, never part of the decision
+    pub user_agent: Option<String>,
+}
+
+impl AuthRequest {
+    pub fn credentials_supplied(&self) -> bool {
+        self.auth_header.is_some()
+            || self.bypass_token_header.is_some()
+            || self.bypass_token_query.is_some()
+    }
+
+    /// The username and password of a Basic `Authorization` header
+    pub fn basic_credentials(&self) -> Option<(Username, String)> {
+        let encoded = self.auth_header.as_ref()?.expose().replace("Basic ", "");
+        let decoded = base64::decode(&encoded)
+            .or_else(|_| base64::decode_config(&encoded, base64::URL_SAFE))
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())?;
+        let mut parts = decoded.splitn(2, ':');
+        let username = parts.next()?.into();
+        Some((username, parts.next()?.to_string()))
+    }
+
+    fn path_token(&self) -> &str {
+        self.path.split('/').last().unwrap_or("")
+    }
+
+    /// The path with its trailing segment replaced by its fingerprint.
+    /// Any segment can be a token, including a mistyped or unknown one, so it is redacted whatever it is.
+    pub fn redacted_path(&self) -> String {
+        let token = self.path_token();
+        if token.is_empty() {
+            return format!("/{}", self.path);
+        }
+        let prefix = &self.path[..self.path.len() - token.len()];
+        format!("/{}{}", prefix, Secret::from(token))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub enum AuthenticationType {
+    BasicAuth,
+    BypassTokenHeader,
+    BypassTokenQuery,
+    BypassTokenPath,
+    ClientIp,
+    Unauthenticated,
+    DomainRegex,
+    Denied,
+}
+
+impl AuthenticationType {
+    pub fn outcome(&self) -> &'static str {
+        match self {
+            Denied => "denied",
+            Unauthenticated => "unauthenticated",
+            _ => "authorized",
+        }
+    }
+}
+
+/// The outcome of `decide` and how it was reached
+#[derive(Debug, Serialize)]
+pub struct Decision {
+    pub authorized: AuthenticationType,
+    pub user: Option<Username>,
+    /// Why the request was refused when `authorized` is `Denied`
+    pub denied: Option<String>,
+    /// A short label for why the request was refused, `None` when it is authorized
+    pub reason: Option<&'static str>,
+    /// Every rule that was evaluated, in order, empty unless `decide` was asked to trace
+    pub trace: Vec<String>,
+}
+
+impl Decision {
+    fn new(authorized: AuthenticationType, user: Option<Username>, trace: Trace) -> Self {
+        Self {
+            authorized,
+            user,
+            denied: None,
+            reason: None,
+            trace: trace.0.unwrap_or_default(),
+        }
+    }
+}
+
+/// The steps of a decision, only formatted when they will be shown
+struct Trace(Option<Vec<String>>);
+
+impl Trace {
+    fn push<F: FnOnce() -> String>(&mut self, step: F) {
+        if let Some(steps) = self.0.as_mut() {
+            steps.push(step());
+        }
+    }
+}
+
+/// The client IP from the `http-client-ip` header, or `x-forwarded-for` if it is missing
+pub fn client_ip(http_client_ip: Option<&str>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+    http_client_ip
+        .or(forwarded_for)
+        .filter(|s| !s.is_empty())
+        .and_then(|h| IpAddr::from_str(h).ok())
+}
+
+/// Decides whether `request` is authorized without changing any state.
+/// The deny list and inactive users are checked first, then domains, IPs, basic auth and tokens in that order.
+/// Each step is recorded in `Decision::trace` when `traced` is set.
+pub fn decide(
+    auth_options: &AuthOptions,
+    ignore_ip: bool,
+    request: &AuthRequest,
+    now: DateTime<Utc>,
+    traced: bool,
+) -> Decision {
+    let mut trace = Trace(if traced { Some(vec![]) } else { None });
+
+    if let Some((label, reason)) = deny_reason(auth_options, request, now) {
+        trace.push(|| format!("Denied: {}", reason));
+        return Decision {
+            denied: Some(reason),
+            reason: Some(label),
+            ..Decision::new(Denied, None, trace)
+        };
+    }
+    trace.push(|| "Nothing in the request is denied".to_string());
+
+    if let Some(host) = request.host.as_ref() {
+        for regex in auth_options.domains.iter() {
+            if regex.is_match(host) {
+                trace.push(|| format!("Domain regex {} matches host {}", regex, host));
+                return Decision::new(DomainRegex, None, trace);
+            }
+            trace.push(|| format!("Domain regex {} does not match host {}", regex, host));
+        }
+    }
+
+    match request.client_ip {
+        Some(_) if ignore_ip => trace.push(|| "IP grants are ignored".to_string()),
+        Some(client_ip) => {
+            if auth_options.ips.contains_key(&client_ip) {
+                trace.push(|| format!("IP {} is authorized", client_ip));
+                return Decision::new(ClientIp, None, trace);
+            }
+            trace.push(|| format!("IP {} is not authorized", client_ip));
+        }
+        None => trace.push(|| "No client IP".to_string()),
+    }
+
+    if request.auth_header.is_some() {
+        match request.basic_credentials() {
+            Some((user, password)) if auth_options.check_password(&user, &password, now) => {
+                trace.push(|| format!("Basic auth matches user {}", user));
+                return Decision::new(BasicAuth, Some(user), trace);
+            }
+            _ => trace.push(|| "Basic auth does not match any user".to_string()),
+        }
+    }
+
+    let tokens = [
+        (
+            BypassTokenQuery,
+            "Query",
+            request.bypass_token_query.as_ref().map(Secret::expose),
+        ),
+        (
+            BypassTokenHeader,
+            "Header",
+            request.bypass_token_header.as_ref().map(Secret::expose),
+        ),
+        (
+            BypassTokenPath,
+            "Path",
+            Some(request.path_token()).filter(|_| !request.path.trim().is_empty()),
+        ),
+    ];
+    for (authorized, source, token) in tokens.iter() {
+        if let Some(token) = token {
+            match auth_options.check_token(&token.to_string()) {
+                Some(user) => {
+                    trace.push(|| format!("{} token matches user {}", source, user));
+                    return Decision::new(*authorized, Some(user), trace);
+                }
+                None => trace.push(|| format!("{} token does not match", source)),
+            }
+        }
+    }
+
+    trace.push(|| "Invalid credentials or IP".to_string());
+    let reason = if request.credentials_supplied() {
+        "invalid_credentials"
+    } else {
+        "missing_credentials"
+    };
+    Decision {
+        reason: Some(reason),
+        ..Decision::new(Unauthenticated, None, trace)
+    }
+}
+
+/// Why the request must be refused before any other check as a label and a description,
+/// `None` if nothing it carries is denied.
+/// Users that are disabled, which includes users added to the deny list, or expired are refused too.
+fn deny_reason(
+    auth_options: &AuthOptions,
+    request: &AuthRequest,
+    now: DateTime<Utc>,
+) -> Option<(&'static str, String)> {
+    let deny = &auth_options.deny;
+    let refuse_user = |user: &Username| auth_options.user_inactive_reason(user, now);
+    if let Some(ip) = request.client_ip.filter(|ip| deny.denies_ip(ip)) {
+        return Some(("denied_ip", format!("IP {} is denied", ip)));
+    }
+
+    let tokens: Vec<&str> = [
+        request.bypass_token_header.as_ref().map(Secret::expose),
+        request.bypass_token_query.as_ref().map(Secret::expose),
+        Some(request.path_token()),
+    ]
+    .iter()
+    .filter_map(|t| *t)
+    .filter(|t| !t.is_empty())
+    .collect();
+    if tokens.iter().any(|t| deny.denies_token(t)) {
+        return Some(("revoked_token", "token is revoked".to_string()));
+    }
+
+    let mut users: Vec<Username> = tokens
+        .iter()
+        .filter_map(|t| auth_options.check_token(&t.to_string()))
+        .collect();
+    // The username is denied whether or not the password is right
+    users.extend(request.basic_credentials().map(|(user, _)| user));
+    for user in users.iter() {
+        if let Some(reason) = refuse_user(user) {
+            return Some(("inactive_user", format!("user {} is {}", user, reason)));
+        }
+    }
+
+    let ip_users = request.client_ip.and_then(|ip| auth_options.ips.get(&ip));
+    if let Some(ip_users) = ip_users.filter(|u| !u.is_empty()) {
+        if ip_users.iter().all(|u| refuse_user(u).is_some()) {
+            return Some((
+                "inactive_user",
+                "every user of the IP grant is disabled or expired".to_string(),
+            ));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn auth_options() -> AuthOptions {
+        let mut auth_options = AuthOptions::default();
+        auth_options.add_password("alice".to_string(), "secret".to_string());
+        auth_options.add_token("alice-token".to_string(), "alice".into());
+        auth_options.add_ip_and_user("10.0.0.1".parse().unwrap(), Some(&"alice".into()));
+        auth_options.add_domain_regex(Regex::new("^public\\.").unwrap());
+        auth_options
+    }
+
+    fn basic(credentials: &str) -> Option<Secret> {
+        Some(Secret::from(format!(
+            "Basic {}",
+            base64::encode_config(credentials, base64::URL_SAFE)
+        )))
+    }
+
+    #[test]
+    fn decide_checks_each_kind_of_auth() {
+        let auth_options = auth_options();
+        let now = Utc::now();
+        let decide = |request: &AuthRequest| decide(&auth_options, false, request, now, false);
+
+        let request = AuthRequest {
+            host: Some("public.example.com".to_string()),
+            ..AuthRequest::default()
+        };
+        assert_eq!(decide(&request).authorized, DomainRegex);
+
+        let request = AuthRequest {
+            client_ip: Some("10.0.0.1".parse().unwrap()),
+            ..AuthRequest::default()
+        };
+        assert_eq!(decide(&request).authorized, ClientIp);
+
+        let request = AuthRequest {
+            auth_header: basic("alice:secret"),
+            ..AuthRequest::default()
+        };
+        let decision = decide(&request);
+        assert_eq!(decision.authorized, BasicAuth);
+        assert_eq!(decision.user, Some("alice".into()));
+
+        let request = AuthRequest {
+            path: "files/alice-token".to_string(),
+            ..AuthRequest::default()
+        };
+        assert_eq!(decide(&request).authorized, BypassTokenPath);
+
+        let request = AuthRequest {
+            auth_header: basic("alice:wrong"),
+            ..AuthRequest::default()
+        };
+        let decision = decide(&request);
+        assert_eq!(decision.authorized, Unauthenticated);
+        assert_eq!(decision.reason, Some("invalid_credentials"));
+        assert_eq!(
+            decide(&AuthRequest::default()).reason,
+            Some("missing_credentials")
+        );
+    }
+
+    #[test]
+    fn decide_refuses_denied_ips_and_disabled_users_first() {
+        let mut auth_options = auth_options();
+        auth_options.deny.ips.push("10.0.0.0/24".parse().unwrap());
+        let now = Utc::now();
+        let request = AuthRequest {
+            client_ip: Some("10.0.0.1".parse().unwrap()),
+            auth_header: basic("alice:secret"),
+            ..AuthRequest::default()
+        };
+        let decision = decide(&auth_options, false, &request, now, false);
+        assert_eq!(decision.authorized, Denied);
+        assert_eq!(decision.reason, Some("denied_ip"));
+
+        auth_options.deny.ips.clear();
+        auth_options.user_mut(&"alice".into()).disabled = true;
+        let decision = decide(&auth_options, false, &request, now, false);
+        assert_eq!(decision.authorized, Denied);
+        assert_eq!(decision.reason, Some("inactive_user"));
+    }
+
+    #[test]
+    fn redacted_path_hides_any_trailing_segment() {
+        let request = |path: &str| AuthRequest {
+            path: path.to_string(),
+            ..AuthRequest::default()
+        };
+        assert_eq!(
+            request("files/not-a-known-token").redacted_path(),
+            format!("/files/{}", Secret::from("not-a-known-token"))
+        );
+        assert_eq!(request("files/").redacted_path(), "/files/");
+        assert_eq!(request("").redacted_path(), "/");
+    }
+
+    #[test]
+    fn decide_only_traces_when_asked() {
+        let auth_options = auth_options();
+        let request = AuthRequest {
+            client_ip: Some("10.0.0.2".parse().unwrap()),
+            bypass_token_query: Some(Secret::from("alice-token")),
+            ..AuthRequest::default()
+        };
+        let decision = decide(&auth_options, false, &request, Utc::now(), false);
+        assert_eq!(decision.authorized, BypassTokenQuery);
+        assert!(decision.trace.is_empty());
+
+        let decision = decide(&auth_options, false, &request, Utc::now(), true);
+        assert_eq!(decision.authorized, BypassTokenQuery);
+        assert_eq!(
+            decision.trace,
+            vec![
+                "Nothing in the request is denied",
+                "IP 10.0.0.2 is not authorized",
+                "Query token matches user alice",
+            ]
+        );
+    }
+}
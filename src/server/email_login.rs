@@ -0,0 +1,388 @@
+use crate::config::auth_options::{AuthOptions, Username};
+use crate::config::command::{CommandContext, Hook};
+use crate::config::redis::IpChange;
+use crate::config::user::Login;
+use crate::server::account::escape_html;
+use crate::server::decision::AuthRequest;
+use crate::server::lockout::Lockout;
+use crate::server::mailer;
+use crate::server::state::{AuthUpdate, State};
+use crate::server::webhooks::sign;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use warp::http::{HeaderValue, StatusCode};
+use warp::Reply;
+
+const AUTH_TYPE: &str = "EmailLink";
+
+/// Served on `GET /_rauthy/login/email`, posts the form back to the same path
+pub const LOGIN_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Log in</title></head>
+<body>
+<h1>Log in</h1>
+<form method="post" action="/_rauthy/login/email">
+<p><label>Email <input name="email" type="email" autocomplete="email" required></label></p>
+<p><button type="submit">Email me a login link</button></p>
+</form>
+</body>
+</html>
+"#;
+
+const INVALID_LINK_PAGE: &str =
+    "<!DOCTYPE html>\n<html><body><p>This login link is invalid, expired or already used.</p></body></html>\n";
+
+#[derive(Deserialize)]
+pub struct LinkRequest {
+    pub email: String,
+}
+
+/// What a link signs, the last login ties it to the user's current state so it can only be used once
+fn payload(username: &Username, expires: i64, last_login_at: Option<DateTime<Utc>>) -> String {
+    format!(
+        "{}\n{}\n{}",
+        username,
+        expires,
+        last_login_at.map(|t| t.to_rfc3339()).unwrap_or_default()
+    )
+}
+
+/// A token for `username` valid until `expires_at`, as `base64(username).expiry.signature`
+fn link_token(
+    secret: &str,
+    auth_options: &AuthOptions,
+    username: &Username,
+    expires_at: DateTime<Utc>,
+) -> String {
+    let last_login_at = auth_options
+        .users
+        .get(username)
+        .and_then(|u| u.last_login_at);
+    let expires = expires_at.timestamp();
+    format!(
+        "{}.{}.{}",
+        base64::encode_config(username.to_string(), base64::URL_SAFE_NO_PAD),
+        expires,
+        sign(secret, &payload(username, expires, last_login_at))
+    )
+}
+
+/// The user `token` logs in, `None` if it is malformed, forged, expired or already used
+fn verify_token(
+    secret: &str,
+    auth_options: &AuthOptions,
+    token: &str,
+    now: DateTime<Utc>,
+) -> Option<Username> {
+    let mut parts = token.splitn(3, '.');
+    let username = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let username: Username = String::from_utf8(username).ok()?.into();
+    let expires: i64 = parts.next()?.parse().ok()?;
+    let signature = hex::decode(parts.next()?).ok()?;
+    if expires <= now.timestamp() {
+        return None;
+    }
+    let last_login_at = auth_options
+        .users
+        .get(&username)
+        .and_then(|u| u.last_login_at);
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload(&username, expires, last_login_at).as_bytes());
+    mac.verify(&signature).ok()?;
+    Some(username)
+}
+
+fn invalid_link() -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::html(INVALID_LINK_PAGE),
+        StatusCode::UNAUTHORIZED,
+    )
+    .into_response()
+}
+
+/// Counts a used, forged or expired link against the caller's IP like a wrong password
+async fn refuse_link(
+    state: &State,
+    request: &AuthRequest,
+    lockout_keys: &[String],
+) -> warp::reply::Response {
+    log::info!("Refused login link from {:?}", request.client_ip);
+    state
+        .metrics
+        .failures
+        .with_label_values(&["invalid_link"])
+        .inc();
+    let context = CommandContext {
+        ip: request.client_ip,
+        auth_type: AUTH_TYPE.to_string(),
+        host: request.host.clone(),
+        path: "/_rauthy/login/email".to_string(),
+        ..CommandContext::default()
+    };
+    state.fire(Hook::OnAuthFailure, context.clone());
+    state.record_failure(lockout_keys, context).await;
+    invalid_link()
+}
+
+/// Emails a login link to the user whose `email` metadata matches, the reply never tells whether it did
+pub async fn request_link(
+    request: LinkRequest,
+    state: Arc<State>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let (smtp, secret) = match (
+        state.config.smtp.clone(),
+        state.config.login_link_secret.as_ref(),
+    ) {
+        (Some(smtp), Some(secret)) => (smtp, secret),
+        _ => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    let reply = warp::reply::json(&json!({
+        "message": "If the address belongs to an account, a login link is on its way"
+    }))
+    .into_response();
+
+    let email = request.email.trim().to_string();
+    let now = Utc::now();
+    let auth_options = state.auth_options();
+    let username = match auth_options
+        .user_by_email(&email)
+        .filter(|_| !email.is_empty())
+    {
+        Some(username) => username.clone(),
+        None => {
+            log::info!("No single user for login link request");
+            return Ok(reply);
+        }
+    };
+    if auth_options.user_status(&username, now) != "active" {
+        log::info!("Not sending a login link to inactive user {}", username);
+        return Ok(reply);
+    }
+
+    let ttl = chrono::Duration::seconds(state.config.login_link_ttl.as_secs() as i64);
+    let token = link_token(secret.expose(), &auth_options, &username, now + ttl);
+    let link = format!(
+        "{}/_rauthy/login/email/{}",
+        state.config.public_url.clone().unwrap_or_default(),
+        token
+    );
+    let body = format!(
+        "Hello {},\n\nOpen this link to log in, it can be used once and expires in {} minutes:\n\n{}\n\nIf you did not ask to log in you can ignore this email.\n",
+        username,
+        ttl.num_minutes(),
+        link
+    );
+    log::info!("Sending a login link to {}", username);
+    // Sent in the background so the reply takes as long whether or not the address is known
+    tokio::spawn(async move {
+        let result = tokio::task::spawn_blocking(move || {
+            mailer::send(&smtp, &email, "Your login link", body)
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::error!("{}", e),
+            Err(e) => log::error!("Login link task failed: {}", e),
+        }
+    });
+    Ok(reply)
+}
+
+/// Served on `GET /_rauthy/login/email/{token}`, asks the user to confirm so link scanners
+/// that follow every link in an email can't use it up
+pub async fn confirm_link(
+    token: String,
+    state: Arc<State>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let secret = match state.config.login_link_secret.as_ref() {
+        Some(secret) => secret,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    let auth_options = state.auth_options();
+    if verify_token(secret.expose(), &auth_options, &token, Utc::now()).is_none() {
+        return Ok(invalid_link());
+    }
+    let page = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Log in</title></head>
+<body>
+<form method="post" action="/_rauthy/login/email/{token}">
+<p><button type="submit">Log in</button></p>
+</form>
+</body>
+</html>
+"#,
+        token = escape_html(&token),
+    );
+    Ok(warp::reply::html(page).into_response())
+}
+
+/// Logs in with a link from `request_link`, granting the caller's IP like any other login
+pub async fn verify_link(
+    token: String,
+    state: Arc<State>,
+    request: AuthRequest,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let secret = match state.config.login_link_secret.as_ref() {
+        Some(secret) => secret,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    let client_ip = match request.client_ip {
+        Some(client_ip) => client_ip,
+        None => return Ok(StatusCode::BAD_REQUEST.into_response()),
+    };
+    let lockout_keys = vec![Lockout::ip_key(client_ip)];
+    if state.lockout.is_locked(&lockout_keys).await {
+        log::info!("Refused login link from locked out {}", client_ip);
+        return Ok(invalid_link());
+    }
+    let now = Utc::now();
+    let auth_options = state.auth_options();
+    let username = verify_token(secret.expose(), &auth_options, &token, now)
+        .filter(|u| auth_options.user_status(u, now) == "active");
+    let username = match username {
+        Some(username) => username,
+        None => return Ok(refuse_link(&state, &request, &lockout_keys).await),
+    };
+    // Replicas only see each other's logins once the store is reloaded, Redis burns the link for all of them
+    if let Some(redis) = state.redis.as_ref() {
+        let key = hex::encode(Sha256::digest(token.as_bytes()));
+        let key = format!("login_link:{}", key);
+        if !redis.burn(&key, state.config.login_link_ttl).await? {
+            return Ok(refuse_link(&state, &request, &lockout_keys).await);
+        }
+    }
+
+    // Recorded before replying, the new last login is what burns the link
+    let grant = AuthUpdate::GrantIp(client_ip, username.clone(), state.grant_expiry());
+    let login = AuthUpdate::RecordLogin(
+        username.clone(),
+        Login {
+            at: now,
+            ip: Some(client_ip),
+            auth_type: AUTH_TYPE.to_string(),
+            host: request.host.clone(),
+            user_agent: request.user_agent.clone(),
+        },
+        state.config.login_history_size,
+    );
+    let new_ip = state
+        .modify(|auth_options| {
+            // Checked again under the writer lock so a link opened twice at once logs in once
+            verify_token(secret.expose(), auth_options, &token, now)?;
+            let new_ip = !auth_options.ips.contains_key(&client_ip);
+            grant.apply(auth_options);
+            login.apply(auth_options);
+            Some(new_ip)
+        })
+        .await?;
+    let new_ip = match new_ip {
+        Some(new_ip) => new_ip,
+        None => return Ok(refuse_link(&state, &request, &lockout_keys).await),
+    };
+    log::info!(
+        "Successful Authentication for '{}' from '{}' with a login link - adding ip to allow list",
+        username,
+        client_ip
+    );
+    state
+        .share_ips(&[IpChange::Grant(client_ip, Some(username.clone()))])
+        .await;
+    let context = CommandContext {
+        user: username,
+        ip: Some(client_ip),
+        auth_type: AUTH_TYPE.to_string(),
+        host: request.host.clone(),
+        path: "/_rauthy/login/email".to_string(),
+        ..CommandContext::default()
+    };
+    if new_ip {
+        state.fire(Hook::OnIpAdded, context.clone());
+    }
+    state.fire(Hook::OnLogin, context);
+
+    let location = state
+        .config
+        .login_redirect
+        .as_ref()
+        .and_then(|url| HeaderValue::from_str(url).ok());
+    Ok(match location {
+        Some(location) => {
+            warp::reply::with_header(StatusCode::SEE_OTHER, "Location", location).into_response()
+        }
+        None => warp::reply::html(
+            "<!DOCTYPE html>\n<html><body><p>You are logged in.</p></body></html>\n",
+        )
+        .into_response(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    const SECRET: &str = "link-secret";
+
+    /// A user and the time a link for them expires, 15 minutes from now
+    fn setup() -> (AuthOptions, Username, DateTime<Utc>, DateTime<Utc>) {
+        let mut auth_options = AuthOptions::default();
+        let username: Username = "alice".into();
+        let now = Utc::now();
+        auth_options.user_mut(&username);
+        (auth_options, username, now, now + Duration::minutes(15))
+    }
+
+    #[test]
+    fn verify_token_accepts_a_fresh_link_until_it_expires() {
+        let (auth_options, username, now, expires_at) = setup();
+        let token = link_token(SECRET, &auth_options, &username, expires_at);
+        assert_eq!(
+            verify_token(SECRET, &auth_options, &token, now),
+            Some(username)
+        );
+        let later = now + Duration::minutes(16);
+        assert_eq!(verify_token(SECRET, &auth_options, &token, later), None);
+    }
+
+    #[test]
+    fn verify_token_rejects_tampered_links() {
+        let (auth_options, username, now, expires_at) = setup();
+        let token = link_token(SECRET, &auth_options, &username, expires_at);
+        let parts: Vec<&str> = token.split('.').collect();
+
+        let other_user = base64::encode_config("mallory", base64::URL_SAFE_NO_PAD);
+        let forged = [
+            format!("{}.{}.{}", other_user, parts[1], parts[2]),
+            format!("{}.{}.{}", parts[0], parts[1].to_string() + "0", parts[2]),
+            format!("{}.{}.{}", parts[0], parts[1], "00".repeat(32)),
+            format!("{}.{}", parts[0], parts[1]),
+        ];
+        for token in forged.iter() {
+            assert_eq!(verify_token(SECRET, &auth_options, token, now), None);
+        }
+        let token = link_token("other-secret", &auth_options, &username, expires_at);
+        assert_eq!(verify_token(SECRET, &auth_options, &token, now), None);
+    }
+
+    #[test]
+    fn verify_token_rejects_a_link_once_the_user_logs_in() {
+        let (mut auth_options, username, now, expires_at) = setup();
+        let token = link_token(SECRET, &auth_options, &username, expires_at);
+        let login = Login {
+            at: now,
+            ip: None,
+            auth_type: AUTH_TYPE.to_string(),
+            host: None,
+            user_agent: None,
+        };
+        auth_options.record_login(&username, login, 20);
+        assert_eq!(verify_token(SECRET, &auth_options, &token, now), None);
+    }
+}
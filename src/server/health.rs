@@ -0,0 +1,181 @@
+use crate::config::auth_file::AuthFile;
+use crate::config::sqlite::SqliteStore;
+use crate::error::RauthyError;
+use crate::server::state::State;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Outcomes of background work, reported by `/readyz`
+pub struct Health {
+    reload: Mutex<Outcome>,
+    write: Mutex<Outcome>,
+    tasks: Mutex<BTreeMap<&'static str, Task>>,
+}
+
+#[derive(Default)]
+struct Outcome {
+    last_success: Option<DateTime<Utc>>,
+    error: Option<String>,
+}
+
+impl Outcome {
+    fn record(&mut self, result: &Result<(), RauthyError>) {
+        match result {
+            Ok(()) => {
+                self.last_success = Some(Utc::now());
+                self.error = None;
+            }
+            Err(e) => self.error = Some(e.to_string()),
+        }
+    }
+
+    fn check(&self) -> Check {
+        Check {
+            ok: self.error.is_none(),
+            error: self.error.clone(),
+            last_success: self.last_success,
+        }
+    }
+}
+
+struct Task {
+    last_seen: DateTime<Utc>,
+    stale_after: Option<Duration>,
+    error: Option<String>,
+}
+
+/// One entry of the readiness report
+#[derive(Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<DateTime<Utc>>,
+}
+
+impl Check {
+    fn from_result(result: Result<(), RauthyError>) -> Self {
+        Check {
+            ok: result.is_ok(),
+            error: result.err().map(|e| e.to_string()),
+            last_success: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    /// `ok` or `degraded`
+    pub status: &'static str,
+    pub checks: BTreeMap<String, Check>,
+}
+
+impl Default for Health {
+    /// The auth options were loaded on start, which counts as the first successful reload
+    fn default() -> Self {
+        Self {
+            reload: Mutex::new(Outcome {
+                last_success: Some(Utc::now()),
+                error: None,
+            }),
+            write: Mutex::new(Outcome::default()),
+            tasks: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Health {
+    pub fn reloaded(&self, result: &Result<(), RauthyError>) {
+        self.reload.lock().unwrap().record(result);
+    }
+
+    pub fn wrote(&self, result: &Result<(), RauthyError>) {
+        self.write.lock().unwrap().record(result);
+    }
+
+    /// Marks `task` as running, it is reported unhealthy if it isn't seen again within `stale_after`
+    pub fn beat(&self, task: &'static str, stale_after: Option<Duration>) {
+        self.tasks.lock().unwrap().insert(
+            task,
+            Task {
+                last_seen: Utc::now(),
+                stale_after,
+                error: None,
+            },
+        );
+    }
+
+    /// Marks `task` as unhealthy until its next `beat`
+    pub fn task_failed(&self, task: &'static str, error: String) {
+        let mut tasks = self.tasks.lock().unwrap();
+        let entry = tasks.entry(task).or_insert(Task {
+            last_seen: Utc::now(),
+            stale_after: None,
+            error: None,
+        });
+        entry.error = Some(error);
+    }
+
+    fn checks(&self, now: DateTime<Utc>) -> BTreeMap<String, Check> {
+        let mut checks = BTreeMap::new();
+        checks.insert("reload".to_string(), self.reload.lock().unwrap().check());
+        checks.insert("write".to_string(), self.write.lock().unwrap().check());
+        for (name, task) in self.tasks.lock().unwrap().iter() {
+            let stale = task
+                .stale_after
+                .and_then(|s| chrono::Duration::from_std(s).ok())
+                .filter(|s| now - task.last_seen > *s)
+                .map(|_| format!("not seen since {}", task.last_seen));
+            let error = task.error.clone().or(stale);
+            checks.insert(
+                format!("task.{}", name),
+                Check {
+                    ok: error.is_none(),
+                    error,
+                    last_success: Some(task.last_seen),
+                },
+            );
+        }
+        checks
+    }
+}
+
+/// Checks the auth file or database, redis and the background work of `state`
+pub async fn readiness(state: &State) -> Readiness {
+    let mut checks = state.health.checks(Utc::now());
+
+    if let Some(database) = state.config.database.clone() {
+        let result =
+            match tokio::task::spawn_blocking(move || SqliteStore::new(database).ping()).await {
+                Ok(result) => result,
+                Err(e) => Err(e.into()),
+            };
+        checks.insert("database".to_string(), Check::from_result(result));
+    } else if let Some(auth_file) = state.config.auth_file.clone() {
+        let file = AuthFile::new(auth_file, 0);
+        let (readable, writable) =
+            tokio::task::spawn_blocking(move || (file.check_readable(), file.check_writable()))
+                .await
+                .unwrap_or_else(|e| {
+                    let error = || Err(RauthyError::ServerError(e.to_string()));
+                    (error(), error())
+                });
+        checks.insert("auth_file.read".to_string(), Check::from_result(readable));
+        checks.insert("auth_file.write".to_string(), Check::from_result(writable));
+    }
+
+    if let Some(redis) = state.redis.as_ref() {
+        checks.insert("redis".to_string(), Check::from_result(redis.ping().await));
+    }
+
+    let status = if checks.values().all(|c| c.ok) {
+        "ok"
+    } else {
+        "degraded"
+    };
+    Readiness { status, checks }
+}
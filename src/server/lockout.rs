@@ -0,0 +1,160 @@
+use crate::config::auth_options::Username;
+use crate::config::config::Config;
+use crate::config::redis::RedisStore;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Failures of one key within the current window
+struct Failures {
+    count: u32,
+    window_start: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Counts authentication failures per IP or user and locks the key out once
+/// `LOCKOUT_THRESHOLD` failures happen within `LOCKOUT_WINDOW`.
+/// The counters live in Redis when it is configured so every replica sees them,
+/// otherwise in memory.
+pub struct Lockout {
+    threshold: u32,
+    window: Duration,
+    duration: Duration,
+    redis: Option<RedisStore>,
+    local: Mutex<HashMap<String, Failures>>,
+}
+
+impl Lockout {
+    pub fn new(config: &Config, redis: Option<RedisStore>) -> Self {
+        Self {
+            threshold: config.lockout_threshold,
+            window: config.lockout_window,
+            duration: config.lockout_duration,
+            redis,
+            local: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn ip_key(ip: IpAddr) -> String {
+        format!("ip:{}", ip)
+    }
+
+    pub fn user_key(username: &Username) -> String {
+        format!("user:{}", username)
+    }
+
+    /// Whether any of `keys` is locked out
+    pub async fn is_locked(&self, keys: &[String]) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+        for key in keys {
+            if let Some(redis) = self.redis.as_ref() {
+                match redis.is_locked_out(key).await {
+                    Ok(true) => return true,
+                    Ok(false) => continue,
+                    Err(e) => log::error!("Failed to read lockout of {} from redis: {}", key, e),
+                }
+            }
+            let local = self.local.lock().unwrap();
+            let locked_until = local.get(key).and_then(|f| f.locked_until);
+            if locked_until.filter(|until| *until > Instant::now()).is_some() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Counts a failure for each of `keys`, returns the keys this failure locked out
+    pub async fn record_failure(&self, keys: &[String]) -> Vec<String> {
+        if self.threshold == 0 {
+            return vec![];
+        }
+        let mut locked = vec![];
+        for key in keys {
+            if let Some(redis) = self.redis.as_ref() {
+                match redis
+                    .record_failure(key, self.threshold, self.window, self.duration)
+                    .await
+                {
+                    Ok(true) => locked.push(key.clone()),
+                    Ok(false) => {}
+                    Err(e) => log::error!("Failed to count failure of {} in redis: {}", key, e),
+                }
+                continue;
+            }
+            if self.record_local(key) {
+                locked.push(key.clone());
+            }
+        }
+        locked
+    }
+
+    fn record_local(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut local = self.local.lock().unwrap();
+        // Forget keys whose window and lockout are over so the map stays small
+        let (window, duration) = (self.window, self.duration);
+        local.retain(|_, f| {
+            now.duration_since(f.window_start) < window
+                || f.locked_until.filter(|until| *until > now).is_some()
+        });
+        let failures = local.entry(key.to_string()).or_insert(Failures {
+            count: 0,
+            window_start: now,
+            locked_until: None,
+        });
+        if now.duration_since(failures.window_start) >= window {
+            failures.count = 0;
+            failures.window_start = now;
+        }
+        failures.count += 1;
+        if failures.count < self.threshold {
+            return false;
+        }
+        failures.count = 0;
+        failures.window_start = now;
+        failures.locked_until = Some(now + duration);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockout(threshold: u32) -> Lockout {
+        Lockout {
+            threshold,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(60),
+            redis: None,
+            local: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn locks_out_at_threshold() {
+        let lockout = lockout(3);
+        let keys = vec![Lockout::ip_key("10.0.0.1".parse().unwrap())];
+        assert!(lockout.record_failure(&keys).await.is_empty());
+        assert!(lockout.record_failure(&keys).await.is_empty());
+        assert!(!lockout.is_locked(&keys).await);
+        assert_eq!(lockout.record_failure(&keys).await, keys);
+        assert!(lockout.is_locked(&keys).await);
+
+        let other = vec![Lockout::ip_key("10.0.0.2".parse().unwrap())];
+        assert!(!lockout.is_locked(&other).await);
+    }
+
+    #[tokio::test]
+    async fn zero_threshold_disables() {
+        let lockout = lockout(0);
+        let keys = vec![Lockout::ip_key("10.0.0.1".parse().unwrap())];
+        for _ in 0..20 {
+            assert!(lockout.record_failure(&keys).await.is_empty());
+        }
+        assert!(!lockout.is_locked(&keys).await);
+    }
+}
@@ -0,0 +1,46 @@
+use crate::config::smtp::{SmtpConfig, SmtpSecurity};
+use crate::error::RauthyError;
+use lettre::smtp::authentication::Credentials;
+use lettre::{ClientSecurity, ClientTlsParameters, SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use native_tls::TlsConnector;
+use std::time::Duration;
+
+/// Sends a plain text email through `smtp`, blocks until the server accepts or refuses it
+pub fn send(smtp: &SmtpConfig, to: &str, subject: &str, body: String) -> Result<(), RauthyError> {
+    let email = EmailBuilder::new()
+        .to(to)
+        .from(smtp.from.as_str())
+        .subject(subject)
+        .text(body)
+        .build()
+        .map_err(|e| RauthyError::ServerError(format!("Unable to build email: {}", e)))?;
+
+    let security = match smtp.security {
+        SmtpSecurity::None => ClientSecurity::None,
+        SmtpSecurity::Tls | SmtpSecurity::StartTls => {
+            let connector = TlsConnector::new()
+                .map_err(|e| RauthyError::ServerError(format!("Unable to set up TLS: {}", e)))?;
+            let parameters = ClientTlsParameters::new(smtp.host.clone(), connector);
+            if smtp.security == SmtpSecurity::Tls {
+                ClientSecurity::Wrapper(parameters)
+            } else {
+                ClientSecurity::Required(parameters)
+            }
+        }
+    };
+    let mut client = SmtpClient::new((smtp.host.as_str(), smtp.port), security)
+        .map_err(|e| RauthyError::ServerError(format!("Unable to reach {}: {}", smtp.host, e)))?
+        .timeout(Some(Duration::from_secs(30)));
+    if let (Some(username), Some(password)) = (smtp.username.as_ref(), smtp.password.as_ref()) {
+        client = client.credentials(Credentials::new(
+            username.clone(),
+            password.expose().to_string(),
+        ));
+    }
+    client
+        .transport()
+        .send(email.into())
+        .map_err(|e| RauthyError::ServerError(format!("Unable to send email to {}: {}", to, e)))?;
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use crate::config::auth_options::{AuthOptions, Username};
+use crate::error::RauthyError;
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::HashSet;
+
+/// Prometheus metrics served on `/metrics`, cloning shares the underlying values
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub decisions: IntCounterVec,
+    pub failures: IntCounterVec,
+    pub decision_duration: Histogram,
+    pub commands: IntCounterVec,
+    pub reloads: IntCounterVec,
+    pub write_errors: IntCounter,
+    users: IntGauge,
+    tokens: IntGauge,
+    ip_grants: IntGauge,
+    domain_regexes: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, RauthyError> {
+        let metrics = Self {
+            registry: Registry::new_custom(Some("rauthy".to_string()), None)?,
+            decisions: IntCounterVec::new(
+                Opts::new("auth_decisions_total", "Auth decisions by type and outcome"),
+                &["auth_type", "outcome"],
+            )?,
+            failures: IntCounterVec::new(
+                Opts::new("auth_failures_total", "Refused auth requests by reason"),
+                &["reason"],
+            )?,
+            decision_duration: Histogram::with_opts(HistogramOpts::new(
+                "auth_decision_duration_seconds",
+                "Time taken to decide an auth request",
+            ))?,
+            commands: IntCounterVec::new(
+                Opts::new("command_executions_total", "User command runs by result"),
+                &["result"],
+            )?,
+            reloads: IntCounterVec::new(
+                Opts::new("config_reloads_total", "Config reloads by result"),
+                &["result"],
+            )?,
+            write_errors: IntCounter::new("write_errors_total", "Failed auth option writes")?,
+            users: IntGauge::new("users", "Known users")?,
+            tokens: IntGauge::new("tokens", "Bypass tokens")?,
+            ip_grants: IntGauge::new("ip_grants", "Authorized IP addresses")?,
+            domain_regexes: IntGauge::new("domain_regexes", "Domain bypass regexes")?,
+        };
+        let collectors: Vec<Box<dyn Collector>> = vec![
+            Box::new(metrics.decisions.clone()),
+            Box::new(metrics.failures.clone()),
+            Box::new(metrics.decision_duration.clone()),
+            Box::new(metrics.commands.clone()),
+            Box::new(metrics.reloads.clone()),
+            Box::new(metrics.write_errors.clone()),
+            Box::new(metrics.users.clone()),
+            Box::new(metrics.tokens.clone()),
+            Box::new(metrics.ip_grants.clone()),
+            Box::new(metrics.domain_regexes.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector)?;
+        }
+        Ok(metrics)
+    }
+
+    /// Renders every metric in the Prometheus text format, gauges are read from `auth_options`
+    pub fn render(&self, auth_options: &AuthOptions) -> Result<String, RauthyError> {
+        let users: HashSet<&Username> = auth_options
+            .passwords
+            .values()
+            .chain(auth_options.tokens.values())
+            .chain(auth_options.ips.values().flatten())
+            .chain(auth_options.commands.keys())
+            .chain(auth_options.users.keys())
+            .collect();
+        self.users.set(users.len() as i64);
+        self.tokens.set(auth_options.tokens.len() as i64);
+        self.ip_grants.set(auth_options.ips.len() as i64);
+        self.domain_regexes.set(auth_options.domains.len() as i64);
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| RauthyError::ServerError(e.to_string()))
+    }
+}
@@ -1 +1,14 @@
+pub mod account;
+pub mod audit;
+pub mod commands;
+pub mod decision;
+pub mod email_login;
+pub mod health;
+pub mod lockout;
+pub mod mailer;
+pub mod metrics;
+pub mod reload;
 pub mod server;
+pub mod state;
+pub mod telemetry;
+pub mod webhooks;
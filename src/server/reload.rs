@@ -0,0 +1,198 @@
+use crate::config::auth_options::AuthOptions;
+use crate::config::config::Config;
+use crate::error::RauthyError;
+use crate::server::state::State;
+use crate::server::telemetry::Span;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::hash::Hash;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+/// Reloads the auth options from disk, keeping the current ones if the new contents are invalid
+pub async fn reload(state: &State) -> Result<(), RauthyError> {
+    let result = load(state).await;
+    state.health.reloaded(&result);
+    let label = if result.is_ok() { "success" } else { "error" };
+    state.metrics.reloads.with_label_values(&[label]).inc();
+    result
+}
+
+async fn load(state: &State) -> Result<(), RauthyError> {
+    let _span = Span::start("store.load");
+    let pending = state.writer().await;
+    let mut new_conf = Config::new().await?;
+    let current = state.auth_options();
+    if state.redis.is_some() {
+        // Redis is the source of truth for IP grants shared between replicas
+        new_conf.auth_options.ips = current.ips.clone();
+    }
+    // Updates that were queued but not written yet are missing from the file
+    for update in pending.iter() {
+        update.apply(&mut new_conf.auth_options);
+    }
+    let summary = summarize_changes(&current, &new_conf.auth_options);
+    state.store(new_conf.auth_options);
+    match summary {
+        Some(summary) => log::info!("Reloaded config: {}", summary),
+        None => log::debug!("Reloaded config, nothing changed"),
+    }
+    Ok(())
+}
+
+/// Reloads on SIGHUP and whenever the auth file changes on disk
+pub async fn watch(state: Arc<State>) -> Result<(), RauthyError> {
+    let (tx, mut rx) = unbounded_channel();
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let sighup_tx = tx.clone();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            log::info!("Received SIGHUP");
+            if sighup_tx.send(()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let watched = state
+        .config
+        .auth_file
+        .clone()
+        .filter(|_| state.config.database.is_none());
+    if let Some(auth_file) = watched {
+        let written = Arc::clone(&state.config.auth_file_written);
+        watch_file(PathBuf::from(auth_file), written, tx)?;
+    }
+
+    tokio::spawn(async move {
+        while rx.recv().await.is_some() {
+            if let Err(e) = reload(&state).await {
+                log::error!("Failed to reload config, keeping the current one: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn watch_file(
+    auth_file: PathBuf,
+    written: Arc<Mutex<Option<String>>>,
+    tx: UnboundedSender<()>,
+) -> Result<(), RauthyError> {
+    // Writes replace the file with a rename so watch the directory rather than the inode
+    let dir = auth_file
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .map(|d| d.to_path_buf())
+        .unwrap_or(PathBuf::from("."));
+    let file_name = auth_file.file_name().map(|f| f.to_os_string());
+
+    let (events_tx, events_rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::watcher(events_tx, Duration::from_millis(500))
+        .map_err(|e| RauthyError::ConfigError(e.to_string()))?;
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .map_err(|e| RauthyError::ConfigError(e.to_string()))?;
+    log::info!("Watching {:?} for changes", auth_file);
+
+    std::thread::spawn(move || {
+        // The watcher stops when dropped so it lives as long as this thread
+        let _watcher = watcher;
+        for event in events_rx {
+            let path = match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Rename(_, path) => path,
+                DebouncedEvent::Error(e, _) => {
+                    log::error!("Error watching {:?}: {}", auth_file, e);
+                    continue;
+                }
+                _ => continue,
+            };
+            if path.file_name().map(|f| f.to_os_string()) == file_name {
+                // Our own writes are already in memory, reloading them would only race with newer updates
+                let hash = fs::read(&path)
+                    .ok()
+                    .map(|contents| hex::encode(Sha256::digest(&contents)));
+                if hash.is_some() && hash == *written.lock().unwrap() {
+                    log::trace!("Ignoring our own write to {:?}", path);
+                    continue;
+                }
+                log::debug!("{:?} changed", path);
+                if tx.send(()).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn count_changes<T: Eq + Hash>(
+    old: impl Iterator<Item = T>,
+    new: impl Iterator<Item = T>,
+) -> (usize, usize) {
+    let old: HashSet<T> = old.collect();
+    let new: HashSet<T> = new.collect();
+    (new.difference(&old).count(), old.difference(&new).count())
+}
+
+/// Describes what was added and removed, `None` if nothing changed
+pub fn summarize_changes(old: &AuthOptions, new: &AuthOptions) -> Option<String> {
+    let changes = vec![
+        (
+            "passwords",
+            count_changes(old.passwords.iter(), new.passwords.iter()),
+        ),
+        (
+            "tokens",
+            count_changes(old.tokens.iter(), new.tokens.iter()),
+        ),
+        ("ips", count_changes(old.ips.keys(), new.ips.keys())),
+        ("users", count_changes(old.users.keys(), new.users.keys())),
+        (
+            "denied",
+            count_changes(
+                old.deny.entries().into_iter(),
+                new.deny.entries().into_iter(),
+            ),
+        ),
+        (
+            "domains",
+            count_changes(
+                old.domains.iter().map(|d| d.as_str()),
+                new.domains.iter().map(|d| d.as_str()),
+            ),
+        ),
+        (
+            "commands",
+            count_changes(
+                old.commands.values().flatten().map(|c| c.to_string()),
+                new.commands.values().flatten().map(|c| c.to_string()),
+            ),
+        ),
+        (
+            "global commands",
+            count_changes(
+                old.global_commands.iter().map(|c| c.to_string()),
+                new.global_commands.iter().map(|c| c.to_string()),
+            ),
+        ),
+    ];
+    let summary: Vec<String> = changes
+        .into_iter()
+        .filter(|(_, (added, removed))| added + removed > 0)
+        .map(|(name, (added, removed))| format!("{} +{} -{}", name, added, removed))
+        .collect();
+    if summary.is_empty() {
+        None
+    } else {
+        Some(summary.join(", "))
+    }
+}
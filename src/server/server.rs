@@ -1,15 +1,33 @@
 use crate::config::auth_options::Username;
-use crate::config::command::UserCommand;
+use crate::config::command::{CommandContext, Hook, UserCommand};
 use crate::config::config::Config;
+use crate::config::deny_list::DenyList;
+use crate::config::redis::{IpChange, IpInvalidation, RedisStore};
+use crate::config::secret::Secret;
+use crate::config::user::Login;
 use crate::error::RauthyError;
-use crate::server::server::AuthenticationType::{
-    BasicAuth, BypassTokenHeader, BypassTokenPath, BypassTokenQuery, ClientIp, Unauthenticated,
+use crate::logging::{self, RequestContext};
+use crate::server::account;
+use crate::server::audit::{describe_deny, AuditEvent, AuditLog};
+use crate::server::decision::AuthenticationType::{
+    BasicAuth, BypassTokenHeader, BypassTokenPath, BypassTokenQuery, ClientIp, Denied,
+    Unauthenticated,
 };
-use serde::Deserialize;
-use std::net::IpAddr;
-use std::str::FromStr;
+use crate::server::decision::{client_ip, decide, AuthRequest};
+use crate::server::email_login;
+use crate::server::health;
+use crate::server::lockout::Lockout;
+use crate::server::metrics::Metrics;
+use crate::server::reload;
+use crate::server::state::{AuthUpdate, State};
+use crate::server::telemetry::{self, Span};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use warp::filters::path::Tail;
 use warp::http::response::Builder;
 use warp::http::{HeaderMap, HeaderValue, StatusCode};
@@ -18,9 +36,23 @@ use warp::{Filter, Reply};
 #[derive(Deserialize)]
 pub struct AddUser {
     pub username: String,
-    pub password: Option<String>,
-    pub token: Option<String>,
+    pub password: Option<Secret>,
+    pub token: Option<Secret>,
     pub command: Option<UserCommand>,
+    pub disabled: Option<bool>,
+    /// Left alone when missing, `null` clears the expiry
+    #[serde(default, deserialize_with = "double_option")]
+    pub expires_at: Option<Option<DateTime<Utc>>>,
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+/// Tells a field set to `null` (`Some(None)`) apart from a missing one (`None`)
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
 }
 
 #[derive(Deserialize, Default)]
@@ -28,74 +60,372 @@ struct AuthQuery {
     token: Option<String>,
 }
 
-#[derive(Debug, PartialEq)]
-enum AuthenticationType {
-    BasicAuth,
-    BypassTokenHeader,
-    BypassTokenQuery,
-    BypassTokenPath,
-    ClientIp,
-    Unauthenticated,
-    DomainRegex,
-}
-
-pub async fn start(config: Config) -> Result<(), RauthyError> {
+pub async fn start(mut config: Config) -> Result<(), RauthyError> {
     let listen = config.listen.clone();
     log::info!("Starting Rauthy on: {:?}", listen);
-    let config = Arc::new(Mutex::new(config));
-    let config = warp::any().map(move || Arc::clone(&config));
+    let redis = config.connect_redis().await?;
+    if let Some(redis) = redis.as_ref() {
+        merge_redis_ips(&mut config, redis).await?;
+    }
+    let _telemetry = telemetry::init(&config)?;
+    let audit = AuditLog::open(&config)?;
+    let state = State::start(config, redis.clone(), Metrics::new()?, audit);
+    if let Some(redis) = redis {
+        tokio::spawn(sync_replicas(redis, Arc::clone(&state)));
+    }
+    reload::watch(Arc::clone(&state)).await?;
+    let shutdown_state = Arc::clone(&state);
+    let state = warp::any().map(move || Arc::clone(&state));
 
     let ips = warp::header::headers_cloned().map(|headers: HeaderMap| {
-        if headers.contains_key("http-client-ip") {
-            headers
-                .get("http-client-ip")
-                .map(|h| h.to_str().unwrap_or(""))
-                .filter(|s| !s.is_empty())
-                .map(|h| IpAddr::from_str(h))
-                .filter(|ip| ip.is_ok())
-                .map(|ip| ip.unwrap())
-        } else if headers.contains_key("x-forwarded-for") {
-            headers
-                .get("x-forwarded-for")
-                .map(|h| h.to_str().unwrap_or(""))
-                .filter(|s| !s.is_empty())
-                .map(|h| IpAddr::from_str(h))
-                .filter(|ip| ip.is_ok())
-                .map(|ip| ip.unwrap())
-        } else {
-            None
-        }
+        let header = |name: &str| headers.get(name).map(|h| h.to_str().unwrap_or(""));
+        client_ip(header("http-client-ip"), header("x-forwarded-for"))
     });
 
-    let status_route = warp::path("status").map(|| StatusCode::OK);
-    let reload_route = warp::path("reload")
-        .and(config.clone())
+    // Rauthy's own endpoints live under `/_rauthy` and the administration under `/_rauthy/admin`,
+    // so they can't shadow the paths being authorized and the two can be routed apart.
+    // The root paths from before the prefix are kept as aliases, `path!` only matches the whole path
+    let rauthy = or_root(warp::path!("_rauthy" / ..));
+    let admin = or_root(warp::path!("_rauthy" / "admin" / ..));
+    let admin_token = warp::header::optional::<String>("x-admin-token")
+        .and(state.clone())
+        .and_then(check_admin_token)
+        .untuple_one();
+    let status_route = rauthy
+        .clone()
+        .and(warp::path!("status"))
+        .map(|| StatusCode::OK);
+    let healthz_route = rauthy
+        .clone()
+        .and(warp::path!("healthz"))
+        .map(|| warp::reply::json(&serde_json::json!({ "status": "ok" })));
+    let readyz_route = rauthy
+        .clone()
+        .and(warp::path!("readyz"))
+        .and(state.clone())
+        .and_then(readyz);
+    let metrics_route = rauthy
+        .clone()
+        .and(warp::path!("metrics"))
+        .and(warp::get())
+        .and(state.clone())
+        .and_then(metrics);
+    let reload_route = admin
+        .clone()
+        .and(warp::path!("reload"))
+        .and(admin_token.clone())
+        .and(state.clone())
         .and_then(reload_config);
-    let user_route = warp::path("user")
+    let user_route = admin
+        .clone()
+        .and(warp::path!("user"))
         .and(warp::post())
+        .and(admin_token.clone())
         .and(warp::body::json())
-        .and(config.clone())
+        .and(state.clone())
         .and_then(add_user);
-    let auth_route = warp::any()
-        .and(config.clone())
-        .and(ips)
+    let users = admin.clone().and(warp::path!("api" / "users" / String));
+    let get_user_route = users
+        .clone()
+        .and(warp::get())
+        .and(admin_token.clone())
+        .and(state.clone())
+        .and_then(get_user);
+    let delete_user_route = users
+        .and(warp::delete())
+        .and(admin_token.clone())
+        .and(state.clone())
+        .and_then(delete_user);
+    let deny = admin.clone().and(warp::path!("api" / "deny"));
+    let deny_route = deny
+        .clone()
+        .and(warp::get())
+        .and(admin_token.clone())
+        .and(state.clone())
+        .map(|state: Arc<State>| warp::reply::json(&state.auth_options().deny_list()))
+        .or(deny
+            .clone()
+            .and(warp::post())
+            .and(admin_token.clone())
+            .and(warp::body::json())
+            .and(state.clone())
+            .and_then(add_deny))
+        .or(deny
+            .and(warp::delete())
+            .and(admin_token)
+            .and(warp::body::json())
+            .and(state.clone())
+            .and_then(remove_deny));
+    let admin_routes = user_route
+        .or(get_user_route)
+        .or(delete_user_route)
+        .or(deny_route)
+        .or(reload_route)
+        .recover(admin_token_required);
+    let account_password = rauthy.clone().and(warp::path!("account" / "password"));
+    let password_route = account_password
+        .clone()
+        .and(warp::get())
+        .map(|| warp::reply::html(account::PASSWORD_PAGE))
+        .or(account_password
+            .and(warp::post())
+            .and(warp::body::json().or(warp::body::form()).unify())
+            .and(ips.clone())
+            .and(state.clone())
+            .and_then(account::change_password));
+    let invite = rauthy.clone().and(warp::path!("invite" / String));
+    let invite_route = invite
+        .clone()
+        .and(warp::get())
+        .and(state.clone())
+        .and_then(account::invite_page)
+        .or(invite
+            .and(warp::post())
+            .and(warp::body::json().or(warp::body::form()).unify())
+            .and(ips.clone())
+            .and(state.clone())
+            .and_then(account::accept_invite));
+    let auth_request = ips
         .and(warp::header::optional::<String>("authorization"))
         .and(warp::header::optional::<String>("x-bypass-token"))
         .and(warp::query().map(|r: AuthQuery| r.token))
         .and(warp::path::tail().map(|s: Tail| s.as_str().to_string()))
         .and(warp::header::optional::<String>("host"))
+        .and(warp::header::optional::<String>("user-agent"))
+        .map(
+            |client_ip,
+             auth_header: Option<String>,
+             bypass_token_header: Option<String>,
+             bypass_token_query: Option<String>,
+             path,
+             host,
+             user_agent| AuthRequest {
+                client_ip,
+                auth_header: auth_header.map(Secret::from),
+                bypass_token_header: bypass_token_header.map(Secret::from),
+                bypass_token_query: bypass_token_query.map(Secret::from),
+                path,
+                host,
+                user_agent,
+            },
+        );
+    let logout_path = rauthy.clone().and(warp::path!("logout"));
+    let logout_route = logout_path
+        .clone()
+        .and(warp::get())
+        .map(|| warp::reply::html(LOGOUT_PAGE))
+        .or(logout_path
+            .and(warp::post())
+            .and(state.clone())
+            .and(auth_request.clone())
+            .and(warp::header::optional::<String>("origin"))
+            .and(warp::header::optional::<String>("referer"))
+            .and_then(logout));
+    let login_email = rauthy.clone().and(warp::path!("login" / "email"));
+    let login_link = rauthy.and(warp::path!("login" / "email" / String));
+    let email_login_route = login_email
+        .clone()
+        .and(warp::get())
+        .map(|| warp::reply::html(email_login::LOGIN_PAGE))
+        .or(login_email
+            .and(warp::post())
+            .and(warp::body::json().or(warp::body::form()).unify())
+            .and(state.clone())
+            .and_then(email_login::request_link))
+        .or(login_link
+            .clone()
+            .and(warp::get())
+            .and(state.clone())
+            .and_then(email_login::confirm_link))
+        .or(login_link
+            .and(warp::post())
+            .and(state.clone())
+            .and(auth_request.clone())
+            .and_then(email_login::verify_link));
+    let auth_route = warp::any()
+        .and(state.clone())
+        .and(auth_request)
+        .and(warp::header::optional::<String>("x-request-id"))
         .and_then(auth);
-    let routes = user_route.or(reload_route).or(status_route).or(auth_route);
+    let routes = admin_routes
+        .or(status_route)
+        .or(healthz_route)
+        .or(readyz_route)
+        .or(logout_route)
+        .or(password_route)
+        .or(invite_route)
+        .or(email_login_route)
+        .or(metrics_route)
+        .or(auth_route);
 
-    warp::serve(routes).run(listen).await;
+    let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(listen, shutdown_signal());
+    server.await;
+    log::info!("Shutting down, persisting queued updates");
+    shutdown_state.flush_queue().await?;
     Ok(())
 }
 
-pub async fn reload_config(config: Arc<Mutex<Config>>) -> Result<impl Reply, warp::Rejection> {
+/// `prefix`, or nothing for the root paths Rauthy's endpoints had before they moved under it
+fn or_root(
+    prefix: impl Filter<Extract = (), Error = warp::Rejection> + Clone,
+) -> impl Filter<Extract = (), Error = std::convert::Infallible> + Clone {
+    prefix
+        .map(|| ())
+        .or(warp::any().map(|| ()))
+        .unify()
+        .untuple_one()
+}
+
+#[derive(Debug)]
+struct AdminTokenRequired;
+
+impl warp::reject::Reject for AdminTokenRequired {}
+
+/// Passes when `ADMIN_TOKEN` is unset or sent in the `x-admin-token` header
+async fn check_admin_token(
+    token: Option<String>,
+    state: Arc<State>,
+) -> Result<(), warp::Rejection> {
+    let expected = match state.config.admin_token.as_ref() {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+    // Compared by digest so the time taken doesn't depend on how much of the token matches
+    let sent = Sha256::digest(token.unwrap_or_default().as_bytes());
+    if sent == Sha256::digest(expected.expose().as_bytes()) {
+        Ok(())
+    } else {
+        Err(warp::reject::custom(AdminTokenRequired))
+    }
+}
+
+/// Answers administration requests without the admin token with a `401`,
+/// anything else goes on to the next route
+async fn admin_token_required(rejection: warp::Rejection) -> Result<StatusCode, warp::Rejection> {
+    if rejection.find::<AdminTokenRequired>().is_some() {
+        Ok(StatusCode::UNAUTHORIZED)
+    } else {
+        Err(rejection)
+    }
+}
+
+async fn shutdown_signal() {
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = terminate.recv() => {},
+            }
+        }
+        Err(e) => {
+            log::error!("Unable to listen for SIGTERM: {}", e);
+            tokio::signal::ctrl_c().await.ok();
+        }
+    }
+}
+
+async fn merge_redis_ips(config: &mut Config, redis: &RedisStore) -> Result<(), RauthyError> {
+    let shared_ips = redis.load_ips().await?;
+    let auth_options = &mut config.auth_options;
+    for (ip, users) in auth_options.ips.iter() {
+        if !shared_ips.contains_key(ip) {
+            redis.change(&IpChange::Grant(*ip, None)).await?;
+            for user in users {
+                redis
+                    .change(&IpChange::Grant(*ip, Some(user.clone())))
+                    .await?;
+            }
+        }
+    }
+    for (ip, users) in shared_ips {
+        auth_options.add_ip_and_user(ip, None);
+        for user in users.iter() {
+            auth_options.add_ip_and_user(ip, Some(user));
+        }
+    }
+    log::info!(
+        "Synchronised {} IP addresses with redis",
+        auth_options.ips.len()
+    );
+    Ok(())
+}
+
+async fn sync_replicas(redis: RedisStore, state: Arc<State>) {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let subscriber = redis.clone();
+    let subscriber_state = Arc::clone(&state);
+    state.health.beat("replica_sync", None);
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = subscriber.subscribe(tx.clone()).await {
+                log::error!("Redis subscription failed: {}", e);
+                subscriber_state
+                    .health
+                    .task_failed("replica_sync", e.to_string());
+            }
+            tokio::time::delay_for(Duration::from_secs(5)).await;
+            // Updates may have been missed while disconnected, request a full resync
+            let resync = IpInvalidation { ip: None };
+            if tx.send(resync).is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some(invalidation) = rx.recv().await {
+        match invalidation.ip {
+            None => match redis.load_ips().await {
+                Ok(ips) => {
+                    log::debug!("Reloaded {} IP addresses from redis", ips.len());
+                    state.health.beat("replica_sync", None);
+                    state.modify_in_memory(|a| a.ips = ips).await;
+                }
+                Err(e) => {
+                    log::error!("Failed to reload IP addresses from redis: {}", e);
+                    state.health.task_failed("replica_sync", e.to_string());
+                }
+            },
+            Some(ip) => match redis.load_ip(ip).await {
+                Ok(Some(users)) => {
+                    log::debug!("Replica authorized IP {} for {:?}", ip, users);
+                    state.modify_in_memory(|a| a.ips.insert(ip, users)).await;
+                }
+                Ok(None) => {
+                    log::debug!("Replica removed IP {}", ip);
+                    state.modify_in_memory(|a| a.remove_ip(&ip)).await;
+                }
+                Err(e) => log::error!("Failed to reload IP {} from redis: {}", ip, e),
+            },
+        }
+    }
+}
+
+pub async fn metrics(state: Arc<State>) -> Result<impl Reply, warp::Rejection> {
+    let body = state.metrics.render(&state.auth_options())?;
+    Ok(warp::reply::with_header(
+        body,
+        "Content-Type",
+        prometheus::TEXT_FORMAT,
+    ))
+}
+
+/// Reports the health of the stores and background tasks, `503` if any check fails
+pub async fn readyz(state: Arc<State>) -> Result<impl Reply, warp::Rejection> {
+    let readiness = health::readiness(&state).await;
+    let status = if readiness.status == "ok" {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&readiness),
+        status,
+    ))
+}
+
+pub async fn reload_config(state: Arc<State>) -> Result<impl Reply, warp::Rejection> {
     log::info!("Reloading config");
-    let new_conf = Config::new().await?;
-    let mut config = config.lock().await;
-    config.auth_options = new_conf.auth_options;
+    reload::reload(&state).await?;
     Ok(StatusCode::OK)
 }
 
@@ -104,163 +434,443 @@ struct InvalidUserName;
 
 impl warp::reject::Reject for InvalidUserName {}
 
-pub async fn add_user(
-    user: AddUser,
-    config: Arc<Mutex<Config>>,
-) -> Result<impl Reply, warp::Rejection> {
-    let mut config = config.lock().await;
+pub async fn add_user(user: AddUser, state: Arc<State>) -> Result<impl Reply, warp::Rejection> {
     let username = user.username.trim().to_string();
     if username.is_empty() {
         log::error!("Empty username");
         return Err(warp::reject::custom(InvalidUserName));
     }
-    if let Some(password) = user
-        .password
-        .map(|p| p.to_string())
-        .filter(|p| !p.is_empty())
-    {
-        config
-            .auth_options
-            .remove_password_by_user(username.clone());
-        config.auth_options.add_password(username.clone(), password);
-        log::info!("Added Basic auth for user: {}", username);
-    }
+    let mut changes = vec![format!("user {}", username)];
+    let revoked = state
+        .modify(|auth_options| {
+            if let Some(password) = user
+                .password
+                .filter(|p| !p.is_empty())
+                .map(Secret::into_inner)
+            {
+                auth_options.remove_password_by_user(username.clone());
+                auth_options.add_password(username.clone(), password);
+                log::info!("Added Basic auth for user: {}", username);
+                changes.push("password".to_string());
+            }
 
-    if let Some(token_string) = user.token.map(|t| t.to_string()).filter(|t| !t.is_empty()) {
-        config.auth_options.remove_token(&token_string);
-        config
-            .auth_options
-            .add_token(token_string, username.clone().into());
-        log::info!("Added Bypass token auth for user: {}", username);
-    }
+            if let Some(token) = user.token.filter(|t| !t.is_empty()) {
+                changes.push(format!("token {}", token));
+                auth_options.remove_token(token.expose());
+                auth_options.add_token(token.into_inner(), username.clone().into());
+                log::info!("Added Bypass token auth for user: {}", username);
+            }
 
-    if let Some(command) = user.command {
-        config
-            .auth_options
-            .add_command(&username.clone().into(), command);
-    }
-    config.write().await?;
+            if let Some(command) = user.command {
+                changes.push(format!("command {}", command));
+                auth_options.add_command(&username.clone().into(), command);
+            }
+
+            let record = auth_options.user_mut(&username.clone().into());
+            if let Some(disabled) = user.disabled {
+                record.disabled = disabled;
+                changes.push(format!("disabled {}", disabled));
+            }
+            if let Some(expires_at) = user.expires_at {
+                record.expires_at = expires_at;
+                match expires_at {
+                    Some(expires_at) => changes.push(format!("expires_at {}", expires_at)),
+                    None => changes.push("expires_at cleared".to_string()),
+                }
+            }
+            if let Some(metadata) = user.metadata {
+                let mut keys: Vec<&String> = metadata.keys().collect();
+                keys.sort();
+                changes.push(format!("metadata {:?}", keys));
+                record.metadata.extend(metadata);
+            }
+            auth_options.revoke_inactive(Utc::now())
+        })
+        .await?;
     log::info!("Stored user details for: {}", username);
+    state.audit(AuditEvent::Admin {
+        source: "api",
+        action: "user.update".to_string(),
+        target: changes.join(", "),
+    });
+    state.revoked(revoked).await;
     Ok(StatusCode::CREATED)
 }
 
-async fn auth(
-    config: Arc<Mutex<Config>>,
-    client_ip: Option<IpAddr>,
-    auth_header: Option<String>,
-    bypass_token_header: Option<String>,
-    bypass_token_query: Option<String>,
-    bypass_token_path: String,
-    host: Option<String>,
+/// The user's details and login history, `404` if the user is not known
+pub async fn get_user(
+    username: String,
+    state: Arc<State>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let username: Username = username.into();
+    let mut details = match state.auth_options().user_details(&username, Utc::now()) {
+        Some(details) => details,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    if let Some(redis) = state.redis.as_ref() {
+        match redis.token_usage(&username).await {
+            Ok(uses) => details.token_uses = Some(uses),
+            Err(e) => log::error!("Failed to read token usage for {}: {}", username, e),
+        }
+    }
+    Ok(warp::reply::json(&details).into_response())
+}
+
+pub async fn delete_user(
+    username: String,
+    state: Arc<State>,
 ) -> Result<impl Reply, warp::Rejection> {
-    log::debug!(
-        "Auth request from {:?} with auth {:?} and query token {:?} header token {:?} path token {:?} from host {:?}",
-        client_ip.clone(),
-        auth_header.clone(),
-        bypass_token_query.clone(),
-        bypass_token_header.clone(),
-        bypass_token_path.clone(),
-        host.clone()
+    let username: Username = username.into();
+    // The user's own commands go with the user, so they are collected before it is removed
+    let removed = state
+        .modify(|a| {
+            let commands = a.commands_for(Hook::OnIpRemoved, Some(&username));
+            a.remove_user(&username).map(|removed| (removed, commands))
+        })
+        .await?;
+    let (removed, commands) = match removed {
+        Some(removed) => removed,
+        None => return Ok(StatusCode::NOT_FOUND),
+    };
+    state.audit(AuditEvent::Admin {
+        source: "api",
+        action: "user.delete".to_string(),
+        target: format!("user {}", username),
+    });
+    log::info!(
+        "Deleted user {} and {} IP addresses",
+        username,
+        removed.removed_ips.len()
     );
+    let changes: Vec<IpChange> = removed
+        .updated_ips
+        .iter()
+        .chain(removed.removed_ips.iter())
+        .map(|ip| IpChange::Revoke(*ip, username.clone()))
+        .collect();
+    state.share_ips(&changes).await;
+    for ip in removed.removed_ips {
+        let context = CommandContext {
+            user: username.clone(),
+            ip: Some(ip),
+            ..CommandContext::default()
+        };
+        state.fire_commands(Hook::OnIpRemoved, context, commands.clone());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    let mut config = config.lock().await;
-    let mut logged_in_user: Option<Username> = None;
-    let mut authorized = Unauthenticated;
+pub async fn add_deny(deny: DenyList, state: Arc<State>) -> Result<impl Reply, warp::Rejection> {
+    log::info!("Adding to deny list: {:?}", deny);
+    let target = describe_deny(&deny);
+    let revoked = state
+        .modify(|auth_options| {
+            auth_options.add_deny(deny);
+            auth_options.revoke_inactive(Utc::now())
+        })
+        .await?;
+    state.audit(AuditEvent::Admin {
+        source: "api",
+        action: "deny.add".to_string(),
+        target,
+    });
+    state.revoked(revoked).await;
+    Ok(StatusCode::CREATED)
+}
 
-    if authorized == Unauthenticated && host.is_some() && !config.auth_options.domains.is_empty() {
-        let host = &host.unwrap();
-        let host_matches = config.auth_options.domains.iter().any(|r| r.is_match(host));
-        if host_matches {
-            log::debug!("Domain matches bypass regex, authorizing");
-            authorized = AuthenticationType::DomainRegex;
+pub async fn remove_deny(deny: DenyList, state: Arc<State>) -> Result<impl Reply, warp::Rejection> {
+    log::info!("Removing from deny list: {:?}", deny);
+    state
+        .modify(|auth_options| auth_options.remove_deny(&deny))
+        .await?;
+    state.audit(AuditEvent::Admin {
+        source: "api",
+        action: "deny.remove".to_string(),
+        target: describe_deny(&deny),
+    });
+    Ok(StatusCode::OK)
+}
+
+/// Served on `GET /_rauthy/logout`, posts back to the same path
+const LOGOUT_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Log out</title></head>
+<body>
+<form method="post" action="/_rauthy/logout">
+<p><button type="submit">Log out</button></p>
+</form>
+</body>
+</html>
+"#;
+
+/// Whether a request came from a page on `host`, by its `Origin` or else its `Referer`.
+/// Requests with neither are refused, browsers send `Origin` with every cross-site `POST`.
+fn same_origin(origin: Option<&str>, referer: Option<&str>, host: Option<&str>) -> bool {
+    let (source, host) = match (origin.or(referer), host) {
+        (Some(source), Some(host)) => (source, host),
+        _ => return false,
+    };
+    let authority = source
+        .find("://")
+        .and_then(|scheme_end| source[scheme_end + 3..].split('/').next())
+        .unwrap_or_default();
+    !authority.is_empty() && authority.eq_ignore_ascii_case(host)
+}
+
+/// Takes the user the caller's credentials identify off the grant for their IP, or the whole grant
+/// without credentials, then redirects to `LOGOUT_REDIRECT_URL` when it is set
+pub async fn logout(
+    state: Arc<State>,
+    request: AuthRequest,
+    origin: Option<String>,
+    referer: Option<String>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let client_ip = match request.client_ip {
+        Some(client_ip) => client_ip,
+        None => return Ok(StatusCode::BAD_REQUEST.into_response()),
+    };
+    if !same_origin(
+        origin.as_deref(),
+        referer.as_deref(),
+        request.host.as_deref(),
+    ) {
+        log::info!("Refused cross-site logout from {}", client_ip);
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    // Credentials sign out only the user they name, without them the caller is signed in through
+    // the IP grant alone, which covers all of its users, so the whole grant goes
+    let user = {
+        let (auth_options, request) = (state.auth_options(), request.clone());
+        tokio::task::spawn_blocking(move || {
+            decide(&auth_options, true, &request, Utc::now(), false)
+        })
+        .await
+        .map_err(RauthyError::from)?
+        .user
+    };
+    let revoked = state
+        .modify(|auth_options| auth_options.revoke_ip(&client_ip, user.as_ref()))
+        .await?;
+    if let Some((users, ip_removed)) = revoked {
+        let names: Vec<String> = users.iter().map(|u| u.to_string()).collect();
+        log::info!("Logged out {:?} from {}", names, client_ip);
+        state.audit(AuditEvent::Admin {
+            source: "user",
+            action: "ip.logout".to_string(),
+            target: format!("ip {} users {}", client_ip, names.join(",")),
+        });
+        let changes: Vec<IpChange> = users
+            .iter()
+            .map(|user| IpChange::Revoke(client_ip, user.clone()))
+            .collect();
+        state.share_ips(&changes).await;
+        for user in users {
+            let context = CommandContext {
+                user,
+                ip: Some(client_ip),
+                host: request.host.clone(),
+                path: "/_rauthy/logout".to_string(),
+                ..CommandContext::default()
+            };
+            state.fire(Hook::OnLogout, context.clone());
+            if ip_removed {
+                state.fire(Hook::OnIpRemoved, context);
+            }
         }
     }
 
-    if authorized == Unauthenticated && client_ip.is_some() && !config.ignore_ip {
-        let client_ip = client_ip.unwrap_or(IpAddr::from([0, 0, 0, 0]));
-        let ip_exists = config.auth_options.ips.contains_key(&client_ip);
-        if ip_exists {
-            log::debug!("IP found, authorizing");
-            authorized = ClientIp;
-        } else {
-            log::debug!("IP not authorized");
+    let location = state
+        .config
+        .logout_redirect
+        .as_ref()
+        .and_then(|url| HeaderValue::from_str(url).ok());
+    Ok(match location {
+        Some(location) => {
+            warp::reply::with_header(StatusCode::SEE_OTHER, "Location", location).into_response()
         }
-    }
+        None => StatusCode::OK.into_response(),
+    })
+}
 
-    if authorized == Unauthenticated && auth_header.is_some() {
-        //Check the basic auth
-        let password_map = &config.auth_options.passwords.clone();
-        let auth_header = auth_header.unwrap();
-        logged_in_user = password_map
-            .get(&auth_header.replace("Basic ", ""))
-            .cloned();
-        if logged_in_user.is_some() {
-            log::debug!("Found basic auth user {:?}", logged_in_user);
-            authorized = BasicAuth;
-        } else {
-            log::debug!("No basic auth user found.");
+/// Decides `request` with its id, from `X-Request-Id` or generated, attached to every log line
+async fn auth(
+    state: Arc<State>,
+    request: AuthRequest,
+    request_id: Option<String>,
+) -> Result<impl Reply, warp::Rejection> {
+    let context = RequestContext {
+        request_id: request_id
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(logging::new_request_id),
+        client_ip: request.client_ip,
+        ..RequestContext::default()
+    };
+    logging::scope(context, authenticate(state, request)).await
+}
+
+async fn authenticate(
+    state: Arc<State>,
+    request: AuthRequest,
+) -> Result<impl Reply, warp::Rejection> {
+    let client_ip = request.client_ip;
+    let auth_options = state.auth_options();
+    log::debug!(
+        "Auth request from {:?} with auth {:?} and query token {:?} header token {:?} path {} from host {:?}",
+        request.client_ip,
+        request.auth_header,
+        request.bypass_token_query,
+        request.bypass_token_header,
+        request.redacted_path(),
+        request.host
+    );
+    let span = Span::start("auth.decision");
+    let timer = state.metrics.decision_duration.start_timer();
+    let traced = log::log_enabled!(log::Level::Debug);
+    let ignore_ip = state.config.ignore_ip;
+    let now = Utc::now();
+    // Checking a password against its bcrypt hash is slow, so Basic auth is decided off the async workers
+    let mut decision = if request.auth_header.is_some() {
+        let (auth_options, request) = (Arc::clone(&auth_options), request.clone());
+        tokio::task::spawn_blocking(move || decide(&auth_options, ignore_ip, &request, now, traced))
+            .await
+            .map_err(RauthyError::from)?
+    } else {
+        decide(&auth_options, ignore_ip, &request, now, traced)
+    };
+    timer.observe_duration();
+    // Correct credentials from a locked out IP are refused too, or guessing would just continue
+    let lockout_keys: Vec<String> = client_ip.map(Lockout::ip_key).into_iter().collect();
+    let credential_auth = [
+        BasicAuth,
+        BypassTokenQuery,
+        BypassTokenHeader,
+        BypassTokenPath,
+    ];
+    if credential_auth.contains(&decision.authorized)
+        && state.lockout.is_locked(&lockout_keys).await
+    {
+        if traced {
+            decision.trace.push("Denied: IP is locked out".to_string());
         }
+        decision.authorized = Denied;
+        decision.denied = Some("IP is locked out after repeated failures".to_string());
+        decision.reason = Some("locked_out");
     }
-
-    if authorized == Unauthenticated && bypass_token_query.is_some() {
-        if let Some(user) = config
-            .auth_options
-            .check_token(&bypass_token_query.unwrap())
-        {
-            authorized = BypassTokenQuery;
-            logged_in_user = Some(user.clone());
-            log::debug!("Query token matched user: {:?}", logged_in_user);
-        };
+    let outcome = decision.authorized.outcome();
+    let auth_type = format!("{:?}", decision.authorized);
+    span.set("auth_type", &auth_type);
+    span.set("outcome", outcome);
+    if let Some(user) = decision.user.as_ref() {
+        span.set("user", user);
     }
-
-    if authorized == Unauthenticated && bypass_token_header.is_some() {
-        if let Some(user) = config
-            .auth_options
-            .check_token(&bypass_token_header.unwrap())
-        {
-            authorized = BypassTokenHeader;
-            logged_in_user = Some(user.clone());
-            log::debug!("Header token matched user: {:?}", logged_in_user);
-        };
+    span.end();
+    logging::update(|context| {
+        context.user = decision.user.clone();
+        context.auth_type = Some(auth_type.clone());
+    });
+    state
+        .metrics
+        .decisions
+        .with_label_values(&[auth_type.as_str(), outcome])
+        .inc();
+    if let Some(reason) = decision.reason {
+        state.metrics.failures.with_label_values(&[reason]).inc();
+    }
+    for step in decision.trace.iter() {
+        log::debug!("{}", step);
     }
+    state.audit(AuditEvent::Decision {
+        client_ip,
+        host: request.host.clone(),
+        path: request.redacted_path(),
+        auth_type,
+        user: decision.user.clone(),
+        outcome,
+        reason: decision
+            .denied
+            .clone()
+            .or(decision.reason.map(|r| r.to_string())),
+    });
+    let authorized = decision.authorized;
+    let logged_in_user = decision.user;
 
-    if authorized == Unauthenticated && !bypass_token_path.trim().is_empty() {
-        let parts = bypass_token_path.split('/').collect::<Vec<&str>>();
-        let token = parts
-            .last()
-            .map(|s| s.to_string())
-            .unwrap_or("".to_string());
-        if let Some(user) = config.auth_options.check_token(&token) {
-            authorized = BypassTokenPath;
-            logged_in_user = Some(user.clone());
-            log::debug!("Path token matched user: {:?}", logged_in_user);
-        } else {
-            log::debug!("No tokens matched");
-        };
+    if let Some(reason) = decision.denied {
+        log::info!("Denied request from {:?}: {}", client_ip, reason);
+        return Ok(Builder::new()
+            .status(StatusCode::FORBIDDEN)
+            .header("X-Rauthy-Authenticated", HeaderValue::from_static("False"))
+            .body("")
+            .unwrap());
     }
 
     if authorized != Unauthenticated && authorized != ClientIp && logged_in_user.clone().is_some() {
         log::debug!("Found user {:?}", logged_in_user);
         let user = logged_in_user.clone().unwrap();
+        let context = CommandContext {
+            user: user.clone(),
+            ip: client_ip,
+            auth_type: format!("{:?}", authorized),
+            host: request.host.clone(),
+            path: request.redacted_path(),
+            ..CommandContext::default()
+        };
         if let Some(client_ip) = client_ip {
             // Add the client ip
-            config.auth_options.add_ip_and_user(client_ip, Some(&user));
-
-            config.write().await?;
+            let new_ip = !auth_options.ips.contains_key(&client_ip);
+            state.queue(AuthUpdate::GrantIp(
+                client_ip,
+                user.clone(),
+                state.grant_expiry(),
+            ));
             log::info!(
                 "Successful Authentication for '{}' from '{}' - adding ip to allow list",
                 user,
                 client_ip.clone()
             );
+            if new_ip {
+                state.fire(Hook::OnIpAdded, context.clone());
+            }
         }
 
-        if let Some(commands) = config.auth_options.commands.get(&user) {
-            for command in commands {
-                log::debug!("Executing command {}", command);
-                let output = command.run();
-                log::trace!("Output results {:#?}", output);
-            }
+        let token_auth = [BypassTokenQuery, BypassTokenHeader, BypassTokenPath];
+        if let Some(redis) = state
+            .redis
+            .clone()
+            .filter(|_| token_auth.contains(&authorized))
+        {
+            let user = user.clone();
+            tokio::spawn(async move {
+                if let Err(e) = redis.record_token_usage(&user).await {
+                    log::error!("Failed to record token usage for {}: {}", user, e);
+                }
+            });
+        }
+
+        let login = Login {
+            at: Utc::now(),
+            ip: client_ip,
+            auth_type: format!("{:?}", authorized),
+            host: request.host.clone(),
+            user_agent: request.user_agent.clone(),
+        };
+        state.queue(AuthUpdate::RecordLogin(
+            user,
+            login,
+            state.config.login_history_size,
+        ));
+        state.fire(Hook::OnLogin, context);
+    }
+
+    if authorized == Unauthenticated && request.credentials_supplied() {
+        let context = CommandContext {
+            ip: client_ip,
+            auth_type: format!("{:?}", authorized),
+            host: request.host.clone(),
+            path: request.redacted_path(),
+            ..CommandContext::default()
         };
+        state.fire(Hook::OnAuthFailure, context.clone());
+        state.record_failure(&lockout_keys, context).await;
     }
 
     let result = match authorized {
@@ -271,8 +881,10 @@ async fn auth(
                 .header("X-Rauthy-Authenticated", HeaderValue::from_static("False"))
                 .header(
                     "WWW-Authenticate",
-                    HeaderValue::from_str(format!("Basic realm=\"{}\"", config.message).as_str())
-                        .unwrap(),
+                    HeaderValue::from_str(
+                        format!("Basic realm=\"{}\"", state.config.message).as_str(),
+                    )
+                    .unwrap(),
                 )
         }
         _ => {
@@ -284,7 +896,7 @@ async fn auth(
                     "X-Rauthy-Auth-Type",
                     HeaderValue::from_str(src.clone().as_str()).unwrap(),
                 );
-            if config.include_user_header && logged_in_user.is_some() {
+            if state.config.include_user_header && logged_in_user.is_some() {
                 let user = logged_in_user.unwrap().to_string();
                 builder = builder.header("X-Rauthy-User", HeaderValue::from_str(&*user).unwrap());
             }
@@ -294,3 +906,27 @@ async fn auth(
 
     Ok(result.body("").unwrap())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_origin_checks_origin_then_referer() {
+        let host = Some("app.example.com");
+        assert!(same_origin(Some("https://app.example.com"), None, host));
+        assert!(same_origin(
+            None,
+            Some("https://app.example.com/_rauthy/logout"),
+            host
+        ));
+        assert!(!same_origin(
+            Some("https://evil.example.net"),
+            Some("https://app.example.com/"),
+            host
+        ));
+        assert!(!same_origin(Some("null"), None, host));
+        assert!(!same_origin(None, None, host));
+        assert!(!same_origin(Some("https://app.example.com"), None, None));
+    }
+}
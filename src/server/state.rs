@@ -0,0 +1,432 @@
+use crate::config::auth_options::{AuthOptions, Username};
+use crate::config::command::{CommandContext, Hook, UserCommand};
+use crate::config::config::Config;
+use crate::config::redis::{IpChange, RedisStore};
+use crate::config::user::Login;
+use crate::error::RauthyError;
+use crate::server::audit::{AuditEvent, AuditLog};
+use crate::server::commands::CommandRunner;
+use crate::server::health::Health;
+use crate::server::lockout::Lockout;
+use crate::server::metrics::Metrics;
+use crate::server::telemetry::Span;
+use crate::server::webhooks::WebhookSender;
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, Mutex, MutexGuard};
+use tokio::time::interval;
+
+/// Changes recorded on the auth path, applied in the background by the update queue
+#[derive(Debug, Clone)]
+pub enum AuthUpdate {
+    GrantIp(IpAddr, Username, Option<DateTime<Utc>>),
+    /// The login and how many entries of the user's history to keep
+    RecordLogin(Username, Login, usize),
+}
+
+impl AuthUpdate {
+    pub fn apply(&self, auth_options: &mut AuthOptions) {
+        match self {
+            AuthUpdate::GrantIp(ip, username, expires_at) => {
+                auth_options.add_ip_and_user(ip.clone(), Some(username));
+                if let Some(expires_at) = expires_at {
+                    auth_options.set_ip_expiry(ip.clone(), expires_at.clone());
+                }
+            }
+            AuthUpdate::RecordLogin(username, login, keep) => {
+                auth_options.record_login(username, login.clone(), *keep)
+            }
+        }
+    }
+}
+
+/// What the update queue carries, a flush request is answered once everything queued before it is persisted
+#[derive(Debug)]
+enum Queued {
+    Update(AuthUpdate),
+    Flush(oneshot::Sender<Result<(), RauthyError>>),
+}
+
+/// Shared server state.
+/// Requests read an immutable snapshot of the auth options which writers replace as a whole,
+/// so the auth decision never waits on a lock.
+/// Queued updates are visible immediately but only persisted when the queue is flushed.
+pub struct State {
+    /// Runtime settings, `config.auth_options` is moved into the snapshot on start
+    pub config: Config,
+    pub redis: Option<RedisStore>,
+    pub commands: CommandRunner,
+    pub metrics: Metrics,
+    pub audit: Option<AuditLog>,
+    pub webhooks: Option<WebhookSender>,
+    pub health: Health,
+    pub lockout: Lockout,
+    auth_options: ArcSwap<AuthOptions>,
+    /// Serialises writers and holds the updates applied since the last write
+    writer: Mutex<Vec<AuthUpdate>>,
+    updates: UnboundedSender<Queued>,
+}
+
+impl State {
+    pub fn start(
+        mut config: Config,
+        redis: Option<RedisStore>,
+        metrics: Metrics,
+        audit: Option<AuditLog>,
+    ) -> Arc<Self> {
+        let auth_options = std::mem::take(&mut config.auth_options);
+        let (updates, rx) = unbounded_channel();
+        let commands = CommandRunner::new(config.command_concurrency, config.command_timeout)
+            .with_metrics(metrics.clone());
+        let webhooks = WebhookSender::start(&config);
+        let lockout = Lockout::new(&config, redis.clone());
+        let state = Arc::new(State {
+            config,
+            redis,
+            commands,
+            metrics,
+            audit,
+            webhooks,
+            health: Health::default(),
+            lockout,
+            auth_options: ArcSwap::from_pointee(auth_options),
+            writer: Mutex::new(vec![]),
+            updates,
+        });
+        tokio::spawn(process_updates(Arc::clone(&state), rx));
+        tokio::spawn(expire_ips(Arc::clone(&state)));
+        state
+    }
+
+    /// When an IP granted now should expire, `None` if grants are permanent
+    pub fn grant_expiry(&self) -> Option<DateTime<Utc>> {
+        self.config
+            .ip_grant_ttl
+            .map(|ttl| Utc::now() + chrono::Duration::seconds(ttl.as_secs() as i64))
+    }
+
+    /// Sends `hook` to the webhooks and runs the global and user commands attached to it in the background
+    pub fn fire(&self, hook: Hook, context: CommandContext) {
+        let commands = self.auth_options().commands_for(hook, Some(&context.user));
+        self.fire_commands(hook, context, commands);
+    }
+
+    /// Like `fire` with commands looked up beforehand, for hooks about users that no longer exist
+    pub fn fire_commands(
+        &self,
+        hook: Hook,
+        mut context: CommandContext,
+        commands: Vec<UserCommand>,
+    ) {
+        if let Some(webhooks) = self.webhooks.as_ref() {
+            webhooks.send(hook, &context);
+        }
+        if !commands.is_empty() {
+            log::debug!("Firing {} for {}", hook, context.user);
+            context.hook = hook;
+            self.commands.spawn(context, commands);
+        }
+    }
+
+    /// Shares changed IP grants with the other replicas
+    pub async fn share_ips(&self, changes: &[IpChange]) {
+        let redis = match self.redis.as_ref() {
+            Some(redis) => redis,
+            None => return,
+        };
+        for change in changes {
+            if let Err(e) = redis.change(change).await {
+                log::error!("Failed to share {:?} with replicas: {}", change, e);
+            }
+        }
+    }
+
+    /// Counts an authentication failure for `keys` and fires `on_lockout` for each key it locks out
+    pub async fn record_failure(&self, keys: &[String], context: CommandContext) {
+        for key in self.lockout.record_failure(keys).await {
+            log::warn!("Locked out {} after repeated authentication failures", key);
+            self.fire(Hook::OnLockout, context.clone());
+        }
+    }
+
+    /// Shares users taken off IP grants by `AuthOptions::revoke_inactive` and fires `on_ip_removed` for dropped IPs
+    pub async fn revoked(&self, revoked: Vec<(IpAddr, Username, bool)>) {
+        let changes: Vec<IpChange> = revoked
+            .iter()
+            .map(|(ip, username, _)| IpChange::Revoke(*ip, username.clone()))
+            .collect();
+        self.share_ips(&changes).await;
+        for (ip, username, ip_removed) in revoked {
+            log::info!("Took inactive user {} off IP {}", username, ip);
+            if ip_removed {
+                let context = CommandContext {
+                    user: username,
+                    ip: Some(ip),
+                    ..CommandContext::default()
+                };
+                self.fire(Hook::OnIpRemoved, context);
+            }
+        }
+    }
+
+    pub fn audit(&self, event: AuditEvent) {
+        if let Some(audit) = self.audit.as_ref() {
+            audit.record(event);
+        }
+    }
+
+    /// The current auth options, never blocks
+    pub fn auth_options(&self) -> Arc<AuthOptions> {
+        self.auth_options.load_full()
+    }
+
+    /// Queues an update without waiting for it to be applied
+    pub fn queue(&self, update: AuthUpdate) {
+        if let Err(e) = self.updates.send(Queued::Update(update)) {
+            log::error!("Update queue closed, dropping {:?}", e.0);
+        }
+    }
+
+    /// Persists every update queued so far, including those the queue has not applied yet.
+    /// Used on shutdown so nothing accepted before it is lost.
+    pub async fn flush_queue(&self) -> Result<(), RauthyError> {
+        let (tx, rx) = oneshot::channel();
+        if self.updates.send(Queued::Flush(tx)).is_err() {
+            return self.flush().await;
+        }
+        rx.await.map_err(|_| {
+            RauthyError::ServerError("Update queue stopped before flushing".to_string())
+        })?
+    }
+
+    /// Serialises writers, hold it while calling `store`.
+    /// The guarded updates have not been persisted yet and must be kept when replacing the auth options.
+    pub async fn writer(&self) -> MutexGuard<'_, Vec<AuthUpdate>> {
+        self.writer.lock().await
+    }
+
+    /// Publishes new auth options without persisting them, callers must hold `writer()`
+    pub fn store(&self, auth_options: AuthOptions) {
+        self.auth_options.store(Arc::new(auth_options));
+    }
+
+    /// Applies `f` to a copy of the current auth options, then persists the result and publishes it.
+    /// Nothing is published if the write fails.
+    pub async fn modify<F, R>(&self, f: F) -> Result<R, RauthyError>
+    where
+        F: FnOnce(&mut AuthOptions) -> R,
+    {
+        let mut pending = self.writer().await;
+        let mut auth_options = AuthOptions::clone(&self.auth_options());
+        let result = f(&mut auth_options);
+        self.write(&auth_options, None).await?;
+        self.store(auth_options);
+        pending.clear();
+        Ok(result)
+    }
+
+    /// Persists any queued updates that have not been written yet
+    pub async fn flush(&self) -> Result<(), RauthyError> {
+        let mut pending = self.writer().await;
+        if pending.is_empty() {
+            return Ok(());
+        }
+        log::debug!("Persisting {} queued updates", pending.len());
+        self.write(&self.auth_options(), Some(&pending)).await?;
+        pending.clear();
+        Ok(())
+    }
+
+    /// Persists `auth_options`, only the rows touched by `updates` if given.
+    /// Callers must hold `writer()`.
+    async fn write(
+        &self,
+        auth_options: &AuthOptions,
+        updates: Option<&[AuthUpdate]>,
+    ) -> Result<(), RauthyError> {
+        let span = Span::start("store.write");
+        let result = match updates {
+            Some(updates) => {
+                let mut ips = HashSet::new();
+                let mut users = HashSet::new();
+                for update in updates {
+                    match update {
+                        AuthUpdate::GrantIp(ip, username, _) => {
+                            ips.insert(*ip);
+                            users.insert(username.clone());
+                        }
+                        AuthUpdate::RecordLogin(username, ..) => {
+                            users.insert(username.clone());
+                        }
+                    }
+                }
+                let ips = ips.into_iter().collect();
+                let users = users.into_iter().collect();
+                self.config.write_changes(auth_options, ips, users).await
+            }
+            None => self.config.write_options(auth_options).await,
+        };
+        if result.is_err() {
+            self.metrics.write_errors.inc();
+        }
+        span.set("success", result.is_ok());
+        self.health.wrote(&result);
+        result
+    }
+
+    /// Same as `modify` for changes that came from the store and must not be written back
+    pub async fn modify_in_memory<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut AuthOptions) -> R,
+    {
+        let _pending = self.writer().await;
+        self.apply(f)
+    }
+
+    /// Replaces the snapshot with the result of `f`, callers must hold `writer()`
+    fn apply<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut AuthOptions) -> R,
+    {
+        let mut auth_options = AuthOptions::clone(&self.auth_options());
+        let result = f(&mut auth_options);
+        self.store(auth_options);
+        result
+    }
+}
+
+async fn process_updates(state: Arc<State>, mut rx: UnboundedReceiver<Queued>) {
+    let mut ticks = interval(state.config.persist_interval);
+    loop {
+        let queued = tokio::select! {
+            queued = rx.recv() => match queued {
+                Some(queued) => queued,
+                None => break,
+            },
+            _ = ticks.tick() => {
+                state.health.beat("update_queue", Some(state.config.persist_interval * 3));
+                if let Err(e) = state.flush().await {
+                    log::error!("Failed to persist queued updates: {}", e);
+                }
+                continue;
+            }
+        };
+
+        // Apply everything already queued in one go, up to a flush request
+        let mut updates = vec![];
+        let mut flush = None;
+        let mut next = Some(queued);
+        while let Some(queued) = next.take() {
+            match queued {
+                Queued::Update(update) => updates.push(update),
+                Queued::Flush(reply) => {
+                    flush = Some(reply);
+                    break;
+                }
+            }
+            next = rx.try_recv().ok();
+        }
+        let pending_count = {
+            let mut pending = state.writer().await;
+            state.apply(|auth_options| {
+                for update in updates.iter() {
+                    update.apply(auth_options);
+                }
+            });
+            pending.extend(updates.iter().cloned());
+            pending.len()
+        };
+        if let Some(reply) = flush {
+            reply.send(state.flush().await).ok();
+        } else if pending_count >= state.config.persist_max_pending {
+            if let Err(e) = state.flush().await {
+                log::error!("Failed to persist queued updates: {}", e);
+            }
+        }
+
+        let grants: Vec<IpChange> = updates
+            .into_iter()
+            .filter_map(|update| match update {
+                AuthUpdate::GrantIp(ip, username, _) => Some(IpChange::Grant(ip, Some(username))),
+                AuthUpdate::RecordLogin(..) => None,
+            })
+            .collect();
+        state.share_ips(&grants).await;
+    }
+}
+
+/// Drops expired IP grants and takes users that expired since the last run off their grants
+async fn expire_ips(state: Arc<State>) {
+    let mut ticks = interval(Duration::from_secs(30));
+    loop {
+        ticks.tick().await;
+        state
+            .health
+            .beat("ip_expiry", Some(Duration::from_secs(90)));
+        expire_grants(&state).await;
+        revoke_expired_users(&state).await;
+    }
+}
+
+async fn revoke_expired_users(state: &State) {
+    let now = Utc::now();
+    if state.auth_options().inactive_grants(now).is_empty() {
+        return;
+    }
+    match state
+        .modify(|auth_options| auth_options.revoke_inactive(now))
+        .await
+    {
+        Ok(revoked) => state.revoked(revoked).await,
+        Err(e) => log::error!("Failed to persist IP grants of expired users: {}", e),
+    }
+}
+
+async fn expire_grants(state: &State) {
+    let expired = state.auth_options().expired_ips(Utc::now());
+    if expired.is_empty() {
+        return;
+    }
+
+    let result = state
+        .modify(|auth_options| {
+            // Checked again under the writer lock, a login may have renewed a grant since
+            let mut removed = vec![];
+            for ip in auth_options.expired_ips(Utc::now()) {
+                removed.push((ip, auth_options.ips.get(&ip).cloned().unwrap_or_default()));
+                auth_options.remove_ip(&ip);
+            }
+            removed
+        })
+        .await;
+    let removed = match result {
+        Ok(removed) => removed,
+        Err(e) => {
+            log::error!("Failed to persist expired IP grants: {}", e);
+            return;
+        }
+    };
+
+    for (ip, users) in removed {
+        log::info!("IP grant for {} expired", ip);
+        state.share_ips(&[IpChange::Remove(ip)]).await;
+        let users = if users.is_empty() {
+            vec![Username::default()]
+        } else {
+            users
+        };
+        for user in users {
+            let context = CommandContext {
+                user,
+                ip: Some(ip),
+                ..CommandContext::default()
+            };
+            state.fire(Hook::OnIpExpired, context);
+        }
+    }
+}
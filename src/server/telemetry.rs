@@ -0,0 +1,91 @@
+use crate::config::config::Config;
+use crate::error::RauthyError;
+use crate::logging;
+
+/// Keeps the OTLP exporter running, pending spans are flushed when it is dropped
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    _uninstall: opentelemetry_otlp::Uninstall,
+}
+
+/// Exports spans to `OTEL_EXPORTER_OTLP_ENDPOINT`, `None` if it is not set.
+/// Without the `otel` feature spans are never recorded.
+#[cfg(feature = "otel")]
+pub fn init(config: &Config) -> Result<Option<Telemetry>, RauthyError> {
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::KeyValue;
+
+    let endpoint = match config.otel_endpoint.as_ref() {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+    let resource = Resource::new(vec![KeyValue::new("service.name", "rauthy")]);
+    let (_, uninstall) = opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .with_trace_config(trace::config().with_resource(resource))
+        .install()
+        .map_err(|e| RauthyError::ConfigError(format!("Unable to start OTLP exporter: {}", e)))?;
+    log::info!("Exporting spans to {}", endpoint);
+    Ok(Some(Telemetry {
+        _uninstall: uninstall,
+    }))
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init(config: &Config) -> Result<Option<Telemetry>, RauthyError> {
+    if config.otel_endpoint.is_some() {
+        log::warn!(
+            "OTEL_EXPORTER_OTLP_ENDPOINT is set but rauthy was built without the otel feature"
+        );
+    }
+    Ok(None)
+}
+
+/// A span that ends when dropped, tagged with the current request id
+pub struct Span {
+    #[cfg(feature = "otel")]
+    inner: opentelemetry::global::BoxedSpan,
+}
+
+impl Span {
+    pub fn start(name: &'static str) -> Self {
+        #[cfg(feature = "otel")]
+        let span = {
+            use opentelemetry::trace::Tracer;
+            Span {
+                inner: opentelemetry::global::tracer("rauthy").start(name),
+            }
+        };
+        #[cfg(not(feature = "otel"))]
+        let span = {
+            let _ = name;
+            Span {}
+        };
+        if let Some(request) = logging::current() {
+            span.set("request_id", request.request_id);
+        }
+        span
+    }
+
+    pub fn set<V: ToString>(&self, key: &'static str, value: V) {
+        #[cfg(feature = "otel")]
+        {
+            use opentelemetry::trace::Span as _;
+            self.inner
+                .set_attribute(opentelemetry::KeyValue::new(key, value.to_string()));
+        }
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, value);
+    }
+
+    /// Ends the span before it goes out of scope
+    pub fn end(self) {}
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Span {
+    fn drop(&mut self) {
+        use opentelemetry::trace::Span as _;
+        self.inner.end();
+    }
+}
@@ -0,0 +1,242 @@
+use crate::config::auth_options::Username;
+use crate::config::command::{CommandContext, Hook};
+use crate::config::config::Config;
+use crate::config::secret::Secret;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::time::delay_for;
+
+/// The JSON body posted to every webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookEvent {
+    pub event: Hook,
+    pub user: Username,
+    pub ip: Option<IpAddr>,
+    pub auth_type: String,
+    pub host: Option<String>,
+    pub path: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl WebhookEvent {
+    pub fn new(event: Hook, context: &CommandContext) -> Self {
+        Self {
+            event,
+            user: context.user.clone(),
+            ip: context.ip,
+            auth_type: context.auth_type.clone(),
+            host: context.host.clone(),
+            path: context.path.clone(),
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+/// Queues events for delivery to `WEBHOOK_URLS`.
+/// Every URL has its own queue and delivery task, so a slow or failing endpoint only delays its own events.
+/// Events are dropped with a warning once `WEBHOOK_QUEUE_SIZE` are waiting so a slow endpoint never holds up auth.
+#[derive(Clone)]
+pub struct WebhookSender {
+    queues: Vec<(String, Sender<Arc<WebhookEvent>>)>,
+    events: Vec<Hook>,
+}
+
+impl WebhookSender {
+    /// Starts the delivery task, `None` if no webhooks are configured
+    pub fn start(config: &Config) -> Option<Self> {
+        if config.webhook_urls.is_empty() {
+            return None;
+        }
+        let client = match reqwest::Client::builder()
+            .timeout(config.webhook_timeout)
+            .build()
+        {
+            Ok(client) => client,
+            Err(e) => {
+                log::error!("Unable to create webhook client, webhooks disabled: {}", e);
+                return None;
+            }
+        };
+        let mut queues = vec![];
+        for url in config.webhook_urls.iter() {
+            let (queue, rx) = channel(config.webhook_queue_size.max(1));
+            let delivery = Delivery {
+                client: client.clone(),
+                url: url.clone(),
+                secret: config.webhook_secret.clone(),
+                retries: config.webhook_retries,
+            };
+            tokio::spawn(delivery.run(rx));
+            queues.push((url.clone(), queue));
+        }
+        Some(Self {
+            queues,
+            events: config.webhook_events.clone(),
+        })
+    }
+
+    /// Queues `hook` without waiting for it to be delivered
+    pub fn send(&self, hook: Hook, context: &CommandContext) {
+        if !self.events.contains(&hook) {
+            return;
+        }
+        let event = Arc::new(WebhookEvent::new(hook, context));
+        for (url, queue) in self.queues.iter() {
+            match queue.clone().try_send(Arc::clone(&event)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(event)) => log::warn!(
+                    "Webhook queue for {} full, dropping {} for {}",
+                    url,
+                    event.event,
+                    event.user
+                ),
+                Err(TrySendError::Closed(event)) => log::error!(
+                    "Webhook queue for {} closed, dropping {} for {}",
+                    url,
+                    event.event,
+                    event.user
+                ),
+            }
+        }
+    }
+}
+
+/// Delivers the events queued for one URL in order
+struct Delivery {
+    client: reqwest::Client,
+    url: String,
+    secret: Option<Secret>,
+    retries: u32,
+}
+
+impl Delivery {
+    async fn run(self, mut rx: Receiver<Arc<WebhookEvent>>) {
+        while let Some(event) = rx.recv().await {
+            let body = match serde_json::to_string(&*event) {
+                Ok(body) => body,
+                Err(e) => {
+                    log::error!("Unable to serialize webhook {}: {}", event.event, e);
+                    continue;
+                }
+            };
+            self.deliver(&event, &body).await;
+        }
+    }
+
+    /// Posts `body` to the URL, retrying with exponential backoff on network errors and 5xx/429 responses
+    async fn deliver(&self, event: &WebhookEvent, body: &str) {
+        let url = self.url.as_str();
+        let mut backoff = Duration::from_secs(1);
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                delay_for(backoff).await;
+                backoff *= 2;
+            }
+            let mut request = self
+                .client
+                .post(url)
+                .header("Content-Type", "application/json")
+                .header("X-Rauthy-Event", event.event.to_string())
+                .body(body.to_string());
+            if let Some(secret) = self.secret.as_ref() {
+                // Signed again for every attempt so receivers can refuse stale timestamps
+                let timestamp = Utc::now().timestamp();
+                let signature = sign(secret.expose(), &signed_payload(timestamp, body));
+                request = request
+                    .header("X-Rauthy-Timestamp", timestamp.to_string())
+                    .header("X-Rauthy-Signature", format!("sha256={}", signature));
+            }
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    log::debug!(
+                        "Webhook {} for {} delivered to {}",
+                        event.event,
+                        event.user,
+                        url
+                    );
+                    return;
+                }
+                Ok(response)
+                    if !response.status().is_server_error()
+                        && response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS =>
+                {
+                    log::warn!(
+                        "Webhook {} for {} rejected by {}: {}",
+                        event.event,
+                        event.user,
+                        url,
+                        response.status()
+                    );
+                    return;
+                }
+                Ok(response) => log::warn!(
+                    "Webhook {} to {} failed (attempt {}): {}",
+                    event.event,
+                    url,
+                    attempt + 1,
+                    response.status()
+                ),
+                Err(e) => log::warn!(
+                    "Webhook {} to {} failed (attempt {}): {}",
+                    event.event,
+                    url,
+                    attempt + 1,
+                    e
+                ),
+            }
+        }
+        log::error!(
+            "Giving up on webhook {} for {} to {} after {} attempts",
+            event.event,
+            event.user,
+            url,
+            self.retries + 1
+        );
+    }
+}
+
+/// What a webhook signature covers, the timestamp keeps a captured delivery from being replayed later
+pub fn signed_payload(timestamp: i64, body: &str) -> String {
+    format!("{}.{}", timestamp, body)
+}
+
+/// Hex encoded HMAC-SHA256 of `body`
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_matches_rfc_4231() {
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn signature_covers_timestamp() {
+        let body = r#"{"event":"on_login"}"#;
+        assert_eq!(
+            signed_payload(1598961600, body),
+            r#"1598961600.{"event":"on_login"}"#
+        );
+        assert_ne!(
+            sign("secret", &signed_payload(1598961600, body)),
+            sign("secret", &signed_payload(1598961601, body))
+        );
+    }
+}